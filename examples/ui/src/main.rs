@@ -3,7 +3,9 @@ use std::thread;
 
 use anyhow::Error;
 use fadetop::app::{FadeTopApp, SamplerOps};
+use fadetop::control::SamplerControl;
 use fadetop::priority::SpiedRecordQueueMap;
+use fadetop::replay::ReplaySampler;
 use py_spy::stack_trace::LocalVariable;
 use py_spy::{Frame, Pid, StackTrace};
 
@@ -11,7 +13,11 @@ use py_spy::{Frame, Pid, StackTrace};
 struct MockSampler {}
 
 impl SamplerOps for MockSampler {
-    fn push_to_queue(self, queue: Arc<RwLock<SpiedRecordQueueMap>>) -> Result<(), Error> {
+    fn push_to_queue(
+        self,
+        queue: Arc<RwLock<SpiedRecordQueueMap>>,
+        _control: SamplerControl,
+    ) -> Result<(), Error> {
         loop {
             for pid in 0..10 {
                 let frame_template = Frame {
@@ -73,7 +79,7 @@ impl SamplerOps for MockSampler {
                         .read()
                         .unwrap()
                         .iter()
-                        .map(|(_, x)| x.finished_events.len())
+                        .map(|(_, x)| x.finished_len())
                         .sum();
                     queue.write().unwrap().increment(&StackTrace {
                         frames: vec![
@@ -136,10 +142,17 @@ impl SamplerOps for MockSampler {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
-    let terminal = ratatui::init();
     let app = FadeTopApp::new(fadetop::config::AppConfig::from_configs()?);
 
-    let result = app.run(terminal, MockSampler {}).await;
+    // pass a file written by `fadetop --record` to replay real data instead
+    let result = match std::env::args().nth(1) {
+        Some(path) => {
+            let sampler = ReplaySampler::open(path)?;
+            let app = app.with_replay_control(sampler.control());
+            app.run(ratatui::init(), sampler).await
+        }
+        None => app.run(ratatui::init(), MockSampler {}).await,
+    };
     ratatui::restore();
     result
 }
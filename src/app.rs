@@ -1,15 +1,31 @@
+use crate::clock::{Clock, system_clock};
 use crate::config::AppConfig;
 
+use crate::control::{CONTROL_POLL, SamplerControl, SamplerSettings};
+
+use crate::discover::python_processes;
 use crate::errors::AppError;
+use crate::export::ExportFormat;
+use crate::launch::LaunchedProcess;
 use crate::priority::SpiedRecordQueueMap;
+use crate::record::SampleRecorder;
+use crate::replay::ReplayControl;
+use crate::tabs::StatefulWidgetExt;
+use crate::tabs::process_selection::{
+    ProcessSelectionOutcome, ProcessSelectionState, ProcessSelectionWidget,
+};
 use crate::{state::AppState, tabs::terminal_event::UpdateEvent};
 use anyhow::Error;
 use py_spy::sampler;
 use ratatui::{DefaultTerminal, crossterm};
+use remoteprocess::Pid;
 
 use std::env;
-use std::sync::RwLock;
-use std::time::Duration;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock, mpsc};
+use std::time::{Duration, Instant};
 use std::{sync::Arc, thread};
 
 impl AppConfig {
@@ -24,26 +40,211 @@ impl AppConfig {
     }
 }
 
+const PROCESS_REFRESH_PERIOD: Duration = Duration::from_secs(1);
+
 pub trait SamplerOps: Send + 'static {
-    fn push_to_queue(self, record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>)
-    -> Result<(), Error>;
+    fn push_to_queue(
+        self,
+        record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>,
+        control: SamplerControl,
+    ) -> Result<(), Error>;
+
+    /// Whether this sampler follows its [`SamplerControl`], which e.g. a replay does not.
+    fn is_controllable(&self) -> bool {
+        false
+    }
+
+    /// The one process this sampler follows until it exits, if there is one.
+    fn target_pid(&self) -> Option<Pid> {
+        None
+    }
+}
+
+// What the UI is told once a sampler gave up with `result`.
+fn stop_reason(result: &Result<(), Error>) -> AppError {
+    match result {
+        Ok(()) => AppError::SamplerExited,
+        Err(err) => AppError::SamplerFailed(format!("{:#}", err)),
+    }
+}
+
+/// Samples stamped with the time they arrived from py-spy, which happens on a
+/// thread of its own so that a backlog behind the queue lock keeps its spacing.
+struct StampedSamples {
+    rx: mpsc::Receiver<(Instant, sampler::Sample)>,
+}
+
+impl StampedSamples {
+    fn spawn<I>(samples: I, clock: Arc<dyn Clock>) -> Self
+    where
+        I: Iterator<Item = sampler::Sample> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for sample in samples {
+                // nobody is listening any more, so stop sampling
+                if tx.send((clock.now(), sample)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { rx }
+    }
+}
+
+impl Iterator for StampedSamples {
+    type Item = (Instant, sampler::Sample);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+/// A py-spy sampler attached to one process, which may also tee every sample it
+/// takes into a recording file.
+pub struct LiveSampler {
+    // `None` while paused
+    sampler: Option<StampedSamples>,
+    pid: Pid,
+    config: py_spy::Config,
+    recorder: Option<SampleRecorder<BufWriter<File>>>,
+}
+
+impl LiveSampler {
+    pub fn new(sampler: sampler::Sampler, pid: Pid, config: py_spy::Config) -> Self {
+        Self {
+            sampler: Some(StampedSamples::spawn(sampler, system_clock())),
+            pid,
+            config,
+            recorder: None,
+        }
+    }
+
+    pub fn with_recorder(mut self, recorder: SampleRecorder<BufWriter<File>>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Attaches to `pid` instead, with the settings `control` currently asks for.
+    pub(crate) fn attach(&mut self, pid: Pid, control: &SamplerControl) -> Result<(), Error> {
+        let settings = control.settings().ok_or(AppError::SamplerSenderError)?;
+        let sampler = sampler::Sampler::new(pid, &settings.apply(&self.config))?;
+        self.sampler = Some(StampedSamples::spawn(sampler, system_clock()));
+        self.pid = pid;
+        Ok(())
+    }
+
+    /// Feeds samples into the queues until the sampled process exits, recreating
+    /// the py-spy sampler whenever `control` changes its settings.
+    pub(crate) fn sample(
+        &mut self,
+        record_queue_map: &RwLock<SpiedRecordQueueMap>,
+        control: &SamplerControl,
+    ) -> Result<(), Error> {
+        let settings = || control.settings().ok_or(AppError::SamplerSenderError);
+        let mut current = settings()?;
+        loop {
+            let sampler = match self.sampler.as_mut() {
+                Some(sampler) => sampler,
+                None => {
+                    self.attach(self.pid, control)?;
+                    current = settings()?;
+                    continue;
+                }
+            };
+            for (taken, sample) in sampler.by_ref() {
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.record(taken, &sample.traces)?;
+                }
+                let mut queue = record_queue_map
+                    .write()
+                    .map_err(|_| AppError::SamplerSenderError)?;
+                queue.increment_sample(
+                    self.pid,
+                    &sample,
+                    taken,
+                    Duration::from_secs(1) / current.sampling_rate.max(1) as u32,
+                );
+                drop(queue);
+                if settings()? != current {
+                    break;
+                }
+            }
+            if settings()? == current {
+                // nothing changed, so the sampler stopped because the process exited
+                return Ok(());
+            }
+
+            // detach before waiting, so that a paused process is not sampled at all
+            self.sampler = None;
+            while settings()?.paused {
+                thread::sleep(CONTROL_POLL);
+            }
+        }
+    }
+}
+
+impl SamplerOps for LiveSampler {
+    fn push_to_queue(
+        mut self,
+        record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>,
+        control: SamplerControl,
+    ) -> Result<(), Error> {
+        self.sample(&record_queue_map, &control)
+    }
+
+    fn is_controllable(&self) -> bool {
+        true
+    }
+
+    fn target_pid(&self) -> Option<Pid> {
+        Some(self.pid)
+    }
 }
 
-impl SamplerOps for sampler::Sampler {
+/// Samples several targets at once, each on its own thread, into the same queues.
+impl<S: SamplerOps> SamplerOps for Vec<S> {
     fn push_to_queue(
         self,
         record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>,
+        control: SamplerControl,
     ) -> Result<(), Error> {
-        for sample in self {
-            for trace in sample.traces.iter() {
-                record_queue_map
-                    .write()
-                    .map_err(|_| AppError::SamplerSenderError)?
-                    .increment(trace);
+        let (tx, rx) = mpsc::channel();
+        for sampler in self {
+            let tx = tx.clone();
+            let queue = Arc::clone(&record_queue_map);
+            let control = control.clone();
+            let pid = sampler.target_pid();
+            thread::spawn(move || {
+                let result = sampler.push_to_queue(Arc::clone(&queue), control.clone());
+                if let Some(pid) = pid {
+                    if result.is_ok()
+                        && let Ok(mut queue) = queue.write()
+                    {
+                        queue.mark_exited(pid);
+                    }
+                    control.target_stopped(pid, stop_reason(&result));
+                }
+                tx.send(result)
+            });
+        }
+        drop(tx);
+        // keep going while any target is still being sampled, then report the first failure
+        let mut first_failure = Ok(());
+        for result in rx {
+            if first_failure.is_ok() {
+                first_failure = result;
             }
         }
+        first_failure
+    }
 
-        Ok(())
+    fn is_controllable(&self) -> bool {
+        self.iter().any(|sampler| sampler.is_controllable())
     }
 }
 
@@ -51,6 +252,36 @@ impl SamplerOps for sampler::Sampler {
 pub struct FadeTopApp {
     pub app_state: AppState,
     update_period: Duration,
+    sampler_control: SamplerControl,
+}
+
+/// Lets the user pick a python process to attach to, or `None` if they quit instead.
+pub fn pick_python_process(terminal: &mut DefaultTerminal) -> Result<Option<Pid>, Error> {
+    let mut state = ProcessSelectionState::default();
+    let mut last_refresh: Option<Instant> = None;
+    loop {
+        if last_refresh.is_none_or(|at| at.elapsed() >= PROCESS_REFRESH_PERIOD) {
+            state.update_processes(python_processes());
+            last_refresh = Some(Instant::now());
+        }
+        terminal.draw(|frame| {
+            frame.render_stateful_widget(
+                ProcessSelectionWidget {}.blocked(),
+                frame.area(),
+                &mut state,
+            )
+        })?;
+        // wake up at least once per refresh so that new processes show up
+        if crossterm::event::poll(PROCESS_REFRESH_PERIOD)?
+            && let crossterm::event::Event::Key(key) = crossterm::event::read()?
+        {
+            match state.handle_focused_event(&key) {
+                ProcessSelectionOutcome::Pending => {}
+                ProcessSelectionOutcome::Selected(pid) => return Ok(Some(pid)),
+                ProcessSelectionOutcome::Cancelled => return Ok(None),
+            }
+        }
+    }
 }
 
 fn send_terminal_event(tx: tokio::sync::mpsc::Sender<UpdateEvent>) -> Result<(), Error> {
@@ -61,21 +292,55 @@ fn send_terminal_event(tx: tokio::sync::mpsc::Sender<UpdateEvent>) -> Result<(),
 
 impl FadeTopApp {
     pub fn new(configs: AppConfig) -> Self {
+        let sampler_control = SamplerControl::new(SamplerSettings::from_config(&configs));
         let mut app_state = AppState::new();
-        app_state
-            .record_queue_map
-            .write()
-            .unwrap()
-            .with_rules(configs.rules);
+        {
+            let mut qmaps = app_state.record_queue_map.write().unwrap();
+            qmaps.with_rules(configs.rules);
+            qmaps.with_compaction(configs.compaction);
+        }
 
         app_state.viewport_bound.width = configs.window_width;
+        app_state.thread_selection.hide_idle = configs.hide_idle_threads;
 
         Self {
             app_state,
             update_period: configs.update_period,
+            sampler_control,
         }
     }
 
+    /// Steer a replay from the UI, with the timeline following the replay position.
+    pub fn with_replay_control(mut self, control: ReplayControl) -> Self {
+        self.app_state.set_clock(Arc::new(control.clone()));
+        self.app_state.replay = Some(control);
+        self
+    }
+
+    /// Take the time from `clock` instead of the system's, e.g. to test rendering.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.app_state.set_clock(clock);
+        self
+    }
+
+    /// Show the state of a process fadetop launched itself.
+    pub fn with_launched(mut self, launched: Arc<Mutex<LaunchedProcess>>) -> Self {
+        self.app_state.launched = Some(launched);
+        self
+    }
+
+    /// Where folded stacks of the visible window are written on key press.
+    pub fn with_folded_path(mut self, path: PathBuf) -> Self {
+        self.app_state.folded_path = path;
+        self
+    }
+
+    /// Export the timeline to `path`, on key press and on exit.
+    pub fn with_export(mut self, format: ExportFormat, path: PathBuf) -> Self {
+        self.app_state.exports.push((format, path));
+        self
+    }
+
     fn run_event_senders<S: SamplerOps>(
         &self,
         sender: tokio::sync::mpsc::Sender<UpdateEvent>,
@@ -85,15 +350,22 @@ impl FadeTopApp {
         thread::spawn({
             let cloned_sender = sender.clone();
             move || {
-                send_terminal_event(cloned_sender).unwrap();
+                if send_terminal_event(cloned_sender.clone()).is_err() {
+                    let _ = cloned_sender
+                        .blocking_send(UpdateEvent::Error(AppError::CrosstermSenderError));
+                }
             }
         });
 
         // Existing sampler event sender
         let queue = Arc::clone(&self.app_state.record_queue_map);
         thread::spawn({
+            let cloned_sender = sender.clone();
+            let control = self.sampler_control.clone().with_events(sender.clone());
             move || {
-                sampler.push_to_queue(queue).unwrap();
+                let err = stop_reason(&sampler.push_to_queue(queue, control));
+                // the UI may already be gone, in which case nobody is left to tell
+                let _ = cloned_sender.blocking_send(UpdateEvent::Error(err));
             }
         });
 
@@ -121,11 +393,129 @@ impl FadeTopApp {
     ) -> Result<(), Error> {
         let (event_tx, mut event_rx) = tokio::sync::mpsc::channel::<UpdateEvent>(2);
 
+        if sampler.is_controllable() {
+            self.app_state.sampler_control = Some(self.sampler_control.clone());
+        }
+
         self.run_event_senders(event_tx, sampler)?;
 
-        self.app_state
+        let result = self
+            .app_state
             .run_until_error(terminal, &mut event_rx)
-            .await?;
-        Ok(())
+            .await;
+
+        // what was sampled is worth keeping however the app ended
+        let exported = self.app_state.export_all();
+        result?;
+        exported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::control::SamplerSettings;
+    use crate::testing::{sample, stack};
+
+    #[test]
+    fn test_backlog_keeps_spacing() {
+        let clock = ManualClock::default();
+        let queues = RwLock::new(SpiedRecordQueueMap::default());
+        queues.write().unwrap().with_clock(Arc::new(clock.clone()));
+        let start = clock.now();
+
+        let ticking = clock.clone();
+        let samples = (0..5).map(move |i| {
+            ticking.advance(Duration::from_millis(10));
+            sample(vec![stack(1, 1, &[&format!("step{}", i)], true, false)])
+        });
+
+        // a slow draw holds the lock until every sample has been taken
+        let held = queues.write().unwrap();
+        let mut live = LiveSampler {
+            sampler: Some(StampedSamples::spawn(samples, Arc::new(clock.clone()))),
+            pid: 1,
+            config: py_spy::Config::default(),
+            recorder: None,
+        };
+        while clock.now() < start + Duration::from_millis(50) {
+            thread::yield_now();
+        }
+        drop(held);
+
+        let control = SamplerControl::new(SamplerSettings {
+            paused: false,
+            sampling_rate: 100,
+            native: false,
+            dump_locals: 0,
+        });
+        live.sample(&queues, &control).unwrap();
+
+        let queues = queues.read().unwrap();
+        let queue = queues.get(&(1, 1)).unwrap();
+        let spans = queue
+            .finished()
+            .map(|record| (record.start - start, record.end - start))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [(10, 15), (15, 25), (25, 35), (35, 45)]
+                .map(|(start, end)| (Duration::from_millis(start), Duration::from_millis(end)))
+        );
+    }
+
+    struct Ends(Pid, bool);
+
+    impl SamplerOps for Ends {
+        fn push_to_queue(
+            self,
+            _record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>,
+            _control: SamplerControl,
+        ) -> Result<(), Error> {
+            match self.1 {
+                true => Ok(()),
+                false => Err(anyhow::anyhow!("permission denied")),
+            }
+        }
+
+        fn target_pid(&self) -> Option<Pid> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn test_each_target_reports_when_it_stops() {
+        let queues = Arc::new(RwLock::new(SpiedRecordQueueMap::default()));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let control = SamplerControl::new(SamplerSettings {
+            paused: false,
+            sampling_rate: 100,
+            native: false,
+            dump_locals: 0,
+        })
+        .with_events(tx);
+
+        let result =
+            vec![Ends(1, true), Ends(2, false)].push_to_queue(Arc::clone(&queues), control);
+        assert!(result.is_err());
+
+        let mut stopped = (0..2)
+            .map(|_| match rx.blocking_recv() {
+                Some(UpdateEvent::TargetStopped(pid, reason)) => (pid, reason.to_string()),
+                _ => panic!("expected a target to stop"),
+            })
+            .collect::<Vec<_>>();
+        stopped.sort();
+        assert_eq!(
+            stopped,
+            vec![
+                (1, "sampling stopped, the target process exited".to_string()),
+                (2, "sampling failed: permission denied".to_string()),
+            ]
+        );
+        let queues = queues.read().unwrap();
+        assert!(queues.is_exited(1));
+        assert!(!queues.is_exited(2));
     }
 }
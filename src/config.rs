@@ -1,7 +1,7 @@
 use std::time::Duration;
 
 use crate::{
-    priority::ForgetRules,
+    priority::{CompactionRule, ForgetRules},
     ser::{parse_duration, parse_locking_strategy},
 };
 use py_spy::config::LockingStrategy;
@@ -53,6 +53,8 @@ pub struct AppConfig {
     pub dump_locals: u64,
     #[serde(default = "default_rules")]
     pub rules: Vec<ForgetRules>,
+    #[serde(default)]
+    pub compaction: Vec<CompactionRule>,
     #[serde(default = "default_update_period")]
     pub update_period: Duration,
     #[serde(
@@ -60,4 +62,7 @@ pub struct AppConfig {
         default = "default_locking_strategy"
     )]
     pub locking_strategy: LockingStrategy,
+    // leave threads that have not run lately out of the thread list
+    #[serde(default)]
+    pub hide_idle_threads: bool,
 }
@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use remoteprocess::Pid;
+use tokio::sync::mpsc::Sender;
+
+use crate::config::AppConfig;
+use crate::errors::AppError;
+use crate::tabs::terminal_event::UpdateEvent;
+
+// How long a paused sampler or replay sleeps before looking at the controls again.
+pub(crate) const CONTROL_POLL: Duration = Duration::from_millis(20);
+
+const MAX_SAMPLING_RATE: u64 = 1000;
+
+/// The py-spy settings that can be changed while sampling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SamplerSettings {
+    pub paused: bool,
+    pub sampling_rate: u64,
+    pub native: bool,
+    pub dump_locals: u64,
+}
+
+impl SamplerSettings {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            paused: false,
+            sampling_rate: config.sampling_rate,
+            native: config.native,
+            dump_locals: config.dump_locals,
+        }
+    }
+
+    /// `config` with these settings applied on top.
+    pub fn apply(&self, config: &py_spy::Config) -> py_spy::Config {
+        py_spy::Config {
+            sampling_rate: self.sampling_rate,
+            native: self.native,
+            dump_locals: self.dump_locals,
+            ..config.clone()
+        }
+    }
+}
+
+/// Shared handle through which the UI steers running samplers, and through which
+/// they tell it about targets that stopped.
+#[derive(Debug, Clone)]
+pub struct SamplerControl {
+    settings: Arc<Mutex<SamplerSettings>>,
+    // only there once the app runs
+    events: Option<Sender<UpdateEvent>>,
+    // the processes some sampler of the session is following
+    claimed: Arc<Mutex<HashSet<Pid>>>,
+}
+
+impl SamplerControl {
+    pub fn new(settings: SamplerSettings) -> Self {
+        Self {
+            settings: Arc::new(Mutex::new(settings)),
+            events: None,
+            claimed: Arc::default(),
+        }
+    }
+
+    pub(crate) fn with_events(mut self, events: Sender<UpdateEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Tells the UI that sampling `pid` stopped for `reason`, while other targets may carry on.
+    pub(crate) fn target_stopped(&self, pid: Pid, reason: AppError) {
+        if let Some(events) = &self.events {
+            // the UI may already be gone, in which case nobody is left to tell
+            let _ = events.blocking_send(UpdateEvent::TargetStopped(pid, reason));
+        }
+    }
+
+    /// Claims `pid` for the sampler asking, unless another sampler of the session has it.
+    pub(crate) fn claim(&self, pid: Pid) -> bool {
+        self.claimed
+            .lock()
+            .is_ok_and(|mut claimed| claimed.insert(pid))
+    }
+
+    /// Lets other samplers claim `pid` again, e.g. once it exited.
+    pub(crate) fn release(&self, pid: Pid) {
+        if let Ok(mut claimed) = self.claimed.lock() {
+            claimed.remove(&pid);
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SamplerSettings)) {
+        if let Ok(mut settings) = self.settings.lock() {
+            f(&mut settings);
+        }
+    }
+
+    pub fn settings(&self) -> Option<SamplerSettings> {
+        self.settings.lock().ok().map(|settings| *settings)
+    }
+
+    pub fn toggle_pause(&self) {
+        self.update(|s| s.paused ^= true);
+    }
+
+    pub fn faster(&self) {
+        self.update(|s| s.sampling_rate = (s.sampling_rate * 2).min(MAX_SAMPLING_RATE));
+    }
+
+    pub fn slower(&self) {
+        self.update(|s| s.sampling_rate = (s.sampling_rate / 2).max(1));
+    }
+
+    pub fn toggle_native(&self) {
+        self.update(|s| s.native ^= true);
+    }
+
+    pub fn toggle_locals(&self) {
+        self.update(|s| s.dump_locals = if s.dump_locals == 0 { 1 } else { 0 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_rate_is_clamped() {
+        let control = SamplerControl::new(SamplerSettings {
+            paused: false,
+            sampling_rate: 2,
+            native: false,
+            dump_locals: 0,
+        });
+        control.slower();
+        control.slower();
+        assert_eq!(control.settings().unwrap().sampling_rate, 1);
+        (0..20).for_each(|_| control.faster());
+        assert_eq!(control.settings().unwrap().sampling_rate, MAX_SAMPLING_RATE);
+    }
+
+    #[test]
+    fn test_claims_are_shared() {
+        let control = SamplerControl::new(SamplerSettings {
+            paused: false,
+            sampling_rate: 100,
+            native: false,
+            dump_locals: 0,
+        });
+        let other = control.clone();
+        assert!(control.claim(1));
+        assert!(!other.claim(1), "claimed by the first sampler");
+        assert!(other.claim(2));
+        control.release(1);
+        assert!(other.claim(1));
+    }
+}
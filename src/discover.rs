@@ -0,0 +1,185 @@
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Error, bail};
+use regex::Regex;
+use remoteprocess::Pid;
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind, Users};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PythonProcess {
+    pub pid: Pid,
+    pub user: Option<String>,
+    pub command_line: String,
+    pub python_version: Option<String>,
+}
+
+fn is_python(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.starts_with("python") || name.starts_with("pypy")
+}
+
+// `python3.11` -> `3.11`
+fn version_from_name(name: &str) -> Option<String> {
+    let version = name
+        .trim_start_matches(|c: char| c.is_alphabetic())
+        .trim_end_matches(".exe");
+    let looks_like_version = !version.is_empty()
+        && version.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && version.chars().next().is_some_and(|c| c.is_ascii_digit());
+    looks_like_version.then(|| version.to_string())
+}
+
+// A bare `python3` binary tells us little, but the libpython it maps does.
+#[cfg(target_os = "linux")]
+fn version_from_maps(pid: Pid) -> Option<String> {
+    std::fs::read_to_string(format!("/proc/{}/maps", pid))
+        .ok()?
+        .lines()
+        .filter_map(|line| line.rsplit('/').next())
+        .filter_map(|file| file.strip_prefix("libpython"))
+        .find_map(|lib| version_from_name(lib.split(".so").next()?))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn version_from_maps(_pid: Pid) -> Option<String> {
+    None
+}
+
+fn python_version(pid: Pid, exe: Option<&Path>) -> Option<String> {
+    exe.and_then(|exe| exe.file_name()?.to_str())
+        .and_then(version_from_name)
+        .or_else(|| version_from_maps(pid))
+}
+
+/// Lists the python processes on this machine that fadetop could attach to, by pid.
+pub fn python_processes() -> Vec<PythonProcess> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::nothing()
+            .without_tasks()
+            .with_cmd(UpdateKind::Always)
+            .with_exe(UpdateKind::Always)
+            .with_user(UpdateKind::Always),
+    );
+    let users = Users::new_with_refreshed_list();
+    let own_pid = std::process::id();
+
+    let mut processes: Vec<PythonProcess> = system
+        .processes()
+        .values()
+        .filter(|process| process.thread_kind().is_none() && process.pid().as_u32() != own_pid)
+        .filter(|process| {
+            let exe_name = process
+                .exe()
+                .and_then(|exe| exe.file_name()?.to_str())
+                .unwrap_or_default();
+            is_python(exe_name) || process.name().to_str().is_some_and(is_python)
+        })
+        .map(|process| {
+            let pid = process.pid().as_u32() as Pid;
+            PythonProcess {
+                pid,
+                user: process
+                    .user_id()
+                    .and_then(|uid| users.get_user_by_id(uid))
+                    .map(|user| user.name().to_string()),
+                command_line: process
+                    .cmd()
+                    .iter()
+                    .map(|arg| arg.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                python_version: python_version(pid, process.exe()),
+            }
+        })
+        .collect();
+    processes.sort_by_key(|p| p.pid);
+    processes
+}
+
+const WAIT_POLL: Duration = Duration::from_millis(500);
+
+impl std::fmt::Display for PythonProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>8} {:<10} {}",
+            self.pid,
+            self.user.as_deref().unwrap_or("?"),
+            self.command_line
+        )
+    }
+}
+
+fn matching_pids(pattern: &Regex, processes: Vec<PythonProcess>) -> Vec<Pid> {
+    processes
+        .into_iter()
+        .filter(|process| pattern.is_match(&process.command_line))
+        .map(|process| process.pid)
+        .collect()
+}
+
+/// Resolves every python process whose command line matches `pattern`,
+/// polling until one appears if `wait` is set.
+pub fn resolve_pids(pattern: &Regex, wait: bool) -> Result<Vec<Pid>, Error> {
+    let mut announced = false;
+    loop {
+        let pids = matching_pids(pattern, python_processes());
+        if !pids.is_empty() {
+            return Ok(pids);
+        }
+        if !wait {
+            bail!("no python process matches '{}'", pattern);
+        }
+        if !announced {
+            eprintln!("waiting for a python process matching '{}'", pattern);
+            announced = true;
+        }
+        thread::sleep(WAIT_POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_from_name() {
+        assert_eq!(version_from_name("python3.11"), Some("3.11".to_string()));
+        assert_eq!(
+            version_from_name("python3.12.exe"),
+            Some("3.12".to_string())
+        );
+        assert_eq!(version_from_name("3.10"), Some("3.10".to_string()));
+        assert_eq!(version_from_name("python"), None);
+        assert_eq!(version_from_name("python-config"), None);
+    }
+
+    #[test]
+    fn test_matching_pids() {
+        let pattern = Regex::new("worker").unwrap();
+        let process = |pid, script| PythonProcess {
+            pid,
+            user: None,
+            command_line: format!("python {} --id {}", script, pid),
+            python_version: None,
+        };
+
+        assert_eq!(matching_pids(&pattern, vec![]), Vec::<Pid>::new());
+        assert_eq!(
+            matching_pids(
+                &pattern,
+                vec![
+                    process(3, "worker.py"),
+                    process(4, "server.py"),
+                    process(5, "worker.py")
+                ]
+            ),
+            vec![3, 5]
+        );
+    }
+}
@@ -1,16 +1,27 @@
 use std::{error::Error, fmt};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub enum AppError {
     SamplerSenderError,
     CrosstermSenderError,
+    SamplerExited,
+    SamplerFailed(String),
+}
+
+impl AppError {
+    /// Whether the app can carry on, showing what was sampled so far.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Self::SamplerExited | Self::SamplerFailed(_))
+    }
 }
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
+        match self {
             Self::SamplerSenderError => write!(f, "sampler"),
             Self::CrosstermSenderError => write!(f, "crossterm"),
+            Self::SamplerExited => write!(f, "sampling stopped, the target process exited"),
+            Self::SamplerFailed(err) => write!(f, "sampling failed: {}", err),
         }
     }
 }
@@ -0,0 +1,511 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::time::{Instant, UNIX_EPOCH};
+
+use anyhow::Error;
+use itertools::Itertools;
+use serde_json::{Value, json};
+
+use crate::priority::{Aggregate, FrameKey, SpiedRecordQueue, SpiedRecordQueueMap};
+
+// A record flattened out of either the finished heap or the live stack.
+struct ExportedRecord<'a> {
+    frame_key: &'a FrameKey,
+    start: Instant,
+    end: Instant,
+    depth: usize,
+    aggregate: Option<Aggregate>,
+    // how much of its time the frame actually ran for, less than all of it once merged
+    busy_share: f64,
+}
+
+fn exported_records(queue: &SpiedRecordQueue) -> Vec<ExportedRecord<'_>> {
+    let mut records: Vec<ExportedRecord> = queue
+        .finished()
+        .map(|r| ExportedRecord {
+            frame_key: &r.frame_key,
+            start: r.start,
+            end: r.end,
+            depth: r.depth,
+            aggregate: r.aggregate,
+            busy_share: r.aggregate.map_or(1.0, |aggregate| {
+                (aggregate.busy.as_secs_f64() / (r.end - r.start).as_secs_f64()).min(1.0)
+            }),
+        })
+        .chain(
+            queue
+                .unfinished_events
+                .iter()
+                .enumerate()
+                .map(|(depth, r)| ExportedRecord {
+                    frame_key: &r.frame_key,
+                    start: r.start,
+                    end: queue.last_update,
+                    depth,
+                    aggregate: None,
+                    busy_share: 1.0,
+                }),
+        )
+        .collect();
+    records.sort_by_key(|r| (r.start, r.depth));
+    records
+}
+
+struct NestingEvent<'a> {
+    at: Instant,
+    open: bool,
+    frame_key: &'a FrameKey,
+    merged: bool,
+    busy_share: f64,
+}
+
+// Replaying these in order opens and closes frames as a well nested stack.
+fn nesting_events<'a>(records: &[ExportedRecord<'a>]) -> Vec<NestingEvent<'a>> {
+    let mut events: Vec<(i64, NestingEvent)> = Vec::new();
+    for record in records.iter().filter(|r| r.end > r.start) {
+        // at equal times, deeper frames close first and shallower frames open first
+        events.push((
+            record.depth as i64,
+            NestingEvent {
+                at: record.start,
+                open: true,
+                frame_key: record.frame_key,
+                merged: record.aggregate.is_some(),
+                busy_share: record.busy_share,
+            },
+        ));
+        events.push((
+            -(record.depth as i64),
+            NestingEvent {
+                at: record.end,
+                open: false,
+                frame_key: record.frame_key,
+                merged: record.aggregate.is_some(),
+                busy_share: record.busy_share,
+            },
+        ));
+    }
+    events.sort_by_key(|(order, e)| (e.at, e.open, *order));
+    events.into_iter().map(|(_, e)| e).collect()
+}
+
+/// Chrome Trace Event Format, loadable by Perfetto UI and chrome://tracing.
+pub fn chrome_trace(qmaps: &SpiedRecordQueueMap) -> Value {
+    let mut events = Vec::new();
+
+    let mut queues: Vec<&SpiedRecordQueue> = qmaps.iter().map(|(_, q)| q).collect();
+    queues.sort_by_key(|q| (q.thread_info.pid, q.thread_info.tid));
+
+    for queue in queues {
+        let info = &queue.thread_info;
+        if let Some(name) = &info.name {
+            events.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": info.pid,
+                "tid": info.tid,
+                "args": {"name": name},
+            }));
+        }
+        for record in exported_records(queue) {
+            let mut args = json!({"file": record.frame_key.filename(), "depth": record.depth});
+            if let Some(aggregate) = record.aggregate {
+                args["merged"] = json!({
+                    "records": aggregate.records,
+                    "calls": aggregate.calls,
+                    "busy_us": aggregate.busy.as_micros() as u64,
+                });
+            }
+            events.push(json!({
+                "name": record.frame_key.name,
+                "cat": "python",
+                "ph": "X",
+                "ts": qmaps.since_start(record.start).as_micros() as u64,
+                "dur": record.end.saturating_duration_since(record.start).as_micros() as u64,
+                "pid": info.pid,
+                "tid": info.tid,
+                "args": args,
+            }));
+        }
+    }
+
+    json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+        "otherData": {
+            "unix_start_micros": qmaps
+                .unix_start()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_micros() as u64),
+        },
+    })
+}
+
+/// speedscope's evented format, one profile per thread over a shared frame table.
+pub fn speedscope(qmaps: &SpiedRecordQueueMap) -> Value {
+    // merged records get frames of their own, as speedscope has nowhere else to tell
+    let mut frame_index: HashMap<(&FrameKey, bool), usize> = HashMap::new();
+    let mut frames = Vec::new();
+    let mut profiles = Vec::new();
+
+    let mut queues: Vec<&SpiedRecordQueue> = qmaps.iter().map(|(_, q)| q).collect();
+    queues.sort_by_key(|q| (q.thread_info.pid, q.thread_info.tid));
+
+    for queue in queues {
+        let events: Vec<(u64, bool, usize)> = nesting_events(&exported_records(queue))
+            .into_iter()
+            .map(|event| {
+                let key = (event.frame_key, event.merged);
+                let frame = *frame_index.entry(key).or_insert_with(|| {
+                    frames.push(json!({
+                        "name": match event.merged {
+                            true => format!("{} (merged)", event.frame_key.name),
+                            false => event.frame_key.name.clone(),
+                        },
+                        "file": event.frame_key.filename(),
+                    }));
+                    frames.len() - 1
+                });
+                (
+                    qmaps.since_start(event.at).as_micros() as u64,
+                    event.open,
+                    frame,
+                )
+            })
+            .collect();
+
+        let info = &queue.thread_info;
+        profiles.push(json!({
+            "type": "evented",
+            "name": match &info.name {
+                Some(name) => format!("{} ({})", name, info.tid),
+                None => format!("{}", info.tid),
+            },
+            "unit": "microseconds",
+            "startValue": events.first().map_or(0, |e| e.0),
+            "endValue": events.last().map_or(0, |e| e.0),
+            "events": events
+                .iter()
+                .map(|(at, open, frame)| {
+                    json!({"type": if *open { "O" } else { "C" }, "frame": frame, "at": at})
+                })
+                .collect::<Vec<Value>>(),
+        }));
+    }
+
+    json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "shared": {"frames": frames},
+        "profiles": profiles,
+        "name": "fadetop",
+        "activeProfileIndex": 0,
+        "exporter": format!("fadetop {}", env!("CARGO_PKG_VERSION")),
+    })
+}
+
+/// Brendan Gregg's folded stacks for the window `left..right`, weighted in
+/// microseconds on screen, with all `queues` merged together.
+///
+/// A record merged out of many short ones only counts for as long as its frame
+/// ran in total, and the rest of its time goes to its caller.
+pub fn folded_stacks<'a>(
+    queues: impl IntoIterator<Item = &'a SpiedRecordQueue>,
+    left: Instant,
+    right: Instant,
+) -> BTreeMap<String, u64> {
+    let mut folded = BTreeMap::new();
+    for queue in queues {
+        let records: Vec<ExportedRecord> = exported_records(queue)
+            .into_iter()
+            .map(|r| ExportedRecord {
+                start: r.start.clamp(left, right),
+                end: r.end.clamp(left, right),
+                ..r
+            })
+            .collect();
+
+        let mut stack: Vec<(&FrameKey, f64)> = Vec::new();
+        let mut since = left;
+        for event in nesting_events(&records) {
+            if !stack.is_empty() && event.at > since {
+                let micros = (event.at - since).as_micros() as f64;
+                let merged = stack.iter().position(|(_, share)| *share < 1.0);
+                let share = merged.map_or(1.0, |depth| stack[depth].1);
+                for (depth, weight) in [
+                    (stack.len(), micros * share),
+                    (merged.unwrap_or(0), micros * (1.0 - share)),
+                ] {
+                    if depth > 0 {
+                        *folded
+                            .entry(
+                                stack[..depth]
+                                    .iter()
+                                    .map(|(key, _)| format!("{} ({})", key.name, key.filename()))
+                                    .join(";"),
+                            )
+                            .or_insert(0) += weight.round() as u64;
+                    }
+                }
+            }
+            since = event.at;
+            if event.open {
+                stack.push((event.frame_key, event.busy_share));
+            } else {
+                stack.pop();
+            }
+        }
+    }
+    folded.retain(|_, weight| *weight > 0);
+    folded
+}
+
+pub fn write_folded<W: Write>(folded: &BTreeMap<String, u64>, mut writer: W) -> Result<(), Error> {
+    for (stack, weight) in folded.iter() {
+        writeln!(writer, "{} {}", stack, weight)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    ChromeTrace,
+    Speedscope,
+}
+
+impl ExportFormat {
+    pub fn write<W: Write>(&self, qmaps: &SpiedRecordQueueMap, writer: W) -> Result<(), Error> {
+        let exported = match self {
+            Self::ChromeTrace => chrome_trace(qmaps),
+            Self::Speedscope => speedscope(qmaps),
+        };
+        serde_json::to_writer(writer, &exported)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::priority::CompactionRule;
+    use crate::testing::stack;
+    use py_spy::StackTrace;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_chrome_trace() {
+        let mut qmaps = SpiedRecordQueueMap::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let named = |tid, names: &[&str]| StackTrace {
+            thread_name: Some(format!("thread{}", tid)),
+            ..stack(1, tid, names, true, false)
+        };
+        qmaps.increment_at(&named(1, &["main", "work"]), at(0));
+        qmaps.increment_at(&named(1, &["main"]), at(10));
+        qmaps.increment_at(&named(2, &["idle"]), at(10));
+        qmaps.increment_at(&named(2, &["idle"]), at(20));
+
+        let exported = chrome_trace(&qmaps);
+        let events = exported["traceEvents"].as_array().unwrap();
+
+        let complete: Vec<(&str, u64, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] == "X")
+            .map(|e| {
+                (
+                    e["name"].as_str().unwrap(),
+                    e["tid"].as_u64().unwrap(),
+                    e["dur"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            complete,
+            vec![("main", 1, 10_000), ("work", 1, 5_000), ("idle", 2, 10_000)]
+        );
+        assert_eq!(events.iter().filter(|e| e["ph"] == "M").count(), 2);
+    }
+
+    #[test]
+    fn test_speedscope() {
+        let mut qmaps = SpiedRecordQueueMap::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let named = |tid, names: &[&str]| StackTrace {
+            thread_name: Some(format!("thread{}", tid)),
+            ..stack(1, tid, names, true, false)
+        };
+        qmaps.increment_at(&named(1, &["main", "work"]), at(0));
+        qmaps.increment_at(&named(1, &["main", "rest"]), at(10));
+        qmaps.increment_at(&named(1, &["main", "work"]), at(20));
+        qmaps.increment_at(&named(1, &[]), at(30));
+
+        let exported = speedscope(&qmaps);
+        let frames = exported["shared"]["frames"].as_array().unwrap();
+        assert_eq!(
+            frames.len(),
+            3,
+            "work should appear once in the frame table"
+        );
+
+        let profiles = exported["profiles"].as_array().unwrap();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0]["name"], "thread1 (1)");
+
+        let events = profiles[0]["events"].as_array().unwrap();
+        // main, then work, rest and work again in it
+        assert_eq!(events.len(), 8);
+        let mut stack = Vec::new();
+        for event in events {
+            let frame = event["frame"].as_u64().unwrap();
+            if event["type"] == "O" {
+                stack.push(frame);
+            } else {
+                assert_eq!(stack.pop(), Some(frame), "events must be well nested");
+            }
+        }
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let mut qmaps = SpiedRecordQueueMap::default();
+        let left = Instant::now();
+        let at = |millis| left + Duration::from_millis(millis);
+        qmaps.increment_at(&stack(1, 1, &["main", "work"], true, false), at(0));
+        qmaps.increment_at(&stack(1, 1, &["main"], true, false), at(2));
+        qmaps.increment_at(&stack(1, 1, &["main"], true, false), at(4));
+        qmaps.increment_at(&stack(1, 2, &["idle"], true, false), at(4));
+        qmaps.increment_at(&stack(1, 2, &[], true, false), at(6));
+        let right = at(6);
+
+        let folded = folded_stacks(qmaps.iter().map(|(_, q)| q), left, right);
+        assert_eq!(
+            folded.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("idle (test.py)".to_string(), 1000),
+                ("main (test.py)".to_string(), 3000),
+                ("main (test.py);work (test.py)".to_string(), 1000)
+            ]
+        );
+
+        // a window that only covers the start of `work`
+        let only_thread1 = folded_stacks(qmaps.get(&(1, 1)), left, left + Duration::from_millis(1));
+        assert_eq!(
+            only_thread1.keys().collect::<Vec<_>>(),
+            vec!["main (test.py);work (test.py)"]
+        );
+    }
+
+    #[test]
+    fn test_merged_records() {
+        let clock = ManualClock::default();
+        let mut qmaps = SpiedRecordQueueMap::default();
+        qmaps.with_clock(Arc::new(clock.clone()));
+        qmaps.with_compaction(vec![CompactionRule {
+            older_than: Duration::from_millis(100),
+            shorter_than: Duration::from_millis(50),
+            bucket: Duration::from_millis(500),
+        }]);
+        let left = clock.now();
+        let at = |millis| left + Duration::from_millis(millis);
+        for (millis, names) in [
+            (0, &["main", "step"][..]),
+            (20, &["main"]),
+            (40, &["main", "step"]),
+            (60, &["main"]),
+            (1000, &["main"]),
+        ] {
+            qmaps.increment_at(&stack(1, 1, names, true, false), at(millis));
+        }
+
+        // two calls of `step`, 30ms in all, were merged into one record over 0..50ms
+        let exported = chrome_trace(&qmaps);
+        let step = exported["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|e| e["name"] == "step")
+            .unwrap();
+        assert_eq!(step["dur"], 50_000);
+        assert_eq!(
+            step["args"]["merged"],
+            json!({"records": 2, "calls": 2, "busy_us": 30_000})
+        );
+
+        let frames = speedscope(&qmaps)["shared"]["frames"].clone();
+        assert_eq!(frames[1]["name"], "step (merged)");
+
+        let folded = folded_stacks(qmaps.iter().map(|(_, q)| q), left, at(100));
+        assert_eq!(
+            folded.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("main (test.py)".to_string(), 70_000),
+                ("main (test.py);step (test.py)".to_string(), 30_000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merged_records_nest_in_speedscope() {
+        let mut qmaps = SpiedRecordQueueMap::default();
+        qmaps.with_compaction(vec![CompactionRule {
+            older_than: Duration::from_millis(100),
+            shorter_than: Duration::from_millis(30),
+            bucket: Duration::from_millis(500),
+        }]);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        for (millis, parent, inner) in [
+            (0, "a", "x"),
+            (10, "a", "z"),
+            (20, "a", "x"),
+            (30, "a", "z"),
+            (40, "b", "y"),
+            (50, "b", "z"),
+            (60, "b", "y"),
+            (70, "b", "z"),
+        ] {
+            qmaps.increment_at(
+                &stack(1, 1, &["main", parent, inner], true, false),
+                at(millis),
+            );
+        }
+        for millis in (80..=1000).step_by(10) {
+            qmaps.increment_at(&stack(1, 1, &["main"], true, false), at(millis));
+        }
+
+        let exported = speedscope(&qmaps);
+        let mut open = Vec::new();
+        for event in exported["profiles"][0]["events"].as_array().unwrap() {
+            match event["type"].as_str().unwrap() {
+                "O" => open.push(event["frame"].as_u64().unwrap()),
+                _ => assert_eq!(open.pop(), event["frame"].as_u64(), "closed out of order"),
+            }
+        }
+        assert!(open.is_empty());
+
+        let samples = crate::import::read_speedscope(exported.to_string().as_bytes(), 100).unwrap();
+        let mut parents = Vec::new();
+        for sample in samples.iter() {
+            let names = sample.traces[0]
+                .frames
+                .iter()
+                .rev()
+                .map(|frame| frame.name.as_str())
+                .collect::<Vec<_>>();
+            if let ["main", parent, inner] = names[..]
+                && inner.ends_with("(merged)")
+            {
+                assert!(!inner.starts_with('x') || parent == "a", "{:?}", names);
+                assert!(!inner.starts_with('y') || parent == "b", "{:?}", names);
+                parents.push(parent);
+            }
+        }
+        parents.dedup();
+        assert_eq!(parents, vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use remoteprocess::Pid;
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+
+use crate::priority::SpiedRecordQueueMap;
+
+// Rates are measured over, and the report refreshed at most once per, this window.
+const HEALTH_WINDOW: Duration = Duration::from_secs(1);
+
+/// How well the samplers are keeping up, as reported by py-spy.
+#[derive(Debug, Clone, Default)]
+pub struct SamplerHealth {
+    recent: HashMap<Pid, VecDeque<Instant>>,
+    pub late: u64,
+    pub errors: u64,
+}
+
+impl SamplerHealth {
+    pub fn note_sample(&mut self, pid: Pid, at: Instant, late: bool, errors: usize) {
+        let recent = self.recent.entry(pid).or_default();
+        recent.push_back(at);
+        while recent
+            .front()
+            .is_some_and(|taken| at.saturating_duration_since(*taken) > HEALTH_WINDOW)
+        {
+            recent.pop_front();
+        }
+        self.late += late as u64;
+        self.errors += errors as u64;
+    }
+
+    /// Samples taken per second lately, and how many processes took them.
+    pub fn rate(&self, now: Instant) -> (f64, usize) {
+        let counts = self
+            .recent
+            .values()
+            .map(|recent| {
+                recent
+                    .iter()
+                    .filter(|taken| now.saturating_duration_since(**taken) <= HEALTH_WINDOW)
+                    .count()
+            })
+            .filter(|count| *count > 0)
+            .collect::<Vec<_>>();
+        (
+            counts.iter().sum::<usize>() as f64 / HEALTH_WINDOW.as_secs_f64(),
+            counts.len(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HealthReport {
+    pub samples_per_sec: f64,
+    pub sampled_processes: usize,
+    pub late: u64,
+    pub errors: u64,
+    pub cpu_percent: f32,
+    pub records: usize,
+    pub record_bytes: usize,
+}
+
+pub fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{}B", bytes),
+        1024..1048576 => format!("{:.1}KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1}MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+/// Keeps a [`HealthReport`] of the samplers and of fadetop itself up to date.
+#[derive(Debug)]
+pub struct HealthMonitor {
+    system: System,
+    own_pid: sysinfo::Pid,
+    refreshed: Option<Instant>,
+    report: HealthReport,
+}
+
+impl Default for HealthMonitor {
+    fn default() -> Self {
+        Self {
+            system: System::new(),
+            own_pid: sysinfo::Pid::from_u32(std::process::id()),
+            refreshed: None,
+            report: HealthReport::default(),
+        }
+    }
+}
+
+impl HealthMonitor {
+    pub fn report(&mut self, qmaps: &SpiedRecordQueueMap, now: Instant) -> HealthReport {
+        if self
+            .refreshed
+            .is_some_and(|at| now.saturating_duration_since(at) < HEALTH_WINDOW)
+        {
+            return self.report;
+        }
+        self.refreshed = Some(now);
+
+        // cpu usage is measured between two refreshes, so the first one reads as zero
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[self.own_pid]),
+            false,
+            ProcessRefreshKind::nothing().with_cpu(),
+        );
+        let health = qmaps.health();
+        let (samples_per_sec, sampled_processes) = health.rate(now);
+        let (records, record_bytes) = qmaps.record_footprint();
+        self.report = HealthReport {
+            samples_per_sec,
+            sampled_processes,
+            late: health.late,
+            errors: health.errors,
+            cpu_percent: self
+                .system
+                .process(self.own_pid)
+                .map_or(0.0, |process| process.cpu_usage()),
+            records,
+            record_bytes,
+        };
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate() {
+        let start = Instant::now();
+        let mut health = SamplerHealth::default();
+        for tenth in 0..30 {
+            let at = start + Duration::from_millis(100 * tenth);
+            health.note_sample(1, at, tenth % 10 == 0, 0);
+            if tenth % 2 == 0 {
+                health.note_sample(2, at, false, 1);
+            }
+        }
+        let (rate, processes) = health.rate(start + Duration::from_millis(2900));
+        assert_eq!(processes, 2);
+        // 11 samples of pid 1 and 5 of pid 2 in the last second, both ends included
+        assert_eq!(rate, 16.0);
+        assert_eq!((health.late, health.errors), (3, 15));
+
+        assert_eq!(health.rate(start + Duration::from_secs(10)), (0.0, 0));
+    }
+}
@@ -0,0 +1,430 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Error, anyhow, bail};
+use remoteprocess::Pid;
+use serde::Deserialize;
+
+use crate::record::{RecordedFrame, RecordedSample, RecordedTrace};
+
+// Lays stacks out one after another on a synthetic clock ticking at the sampling rate.
+struct SyntheticClock {
+    period: Duration,
+    samples: Vec<(Duration, RecordedTrace)>,
+}
+
+impl SyntheticClock {
+    fn new(sampling_rate: u64) -> Self {
+        Self {
+            period: Duration::from_secs(1) / sampling_rate.max(1) as u32,
+            samples: Vec::new(),
+        }
+    }
+
+    // Root first frames are sampled at every tick in `from..from + lasting`.
+    fn push_stack(
+        &mut self,
+        thread: &ThreadKey,
+        stack: &[RecordedFrame],
+        from: Duration,
+        lasting: Duration,
+    ) {
+        let ticks = (lasting.div_duration_f64(self.period).round() as u64).max(1);
+        for tick in 0..ticks {
+            self.samples.push((
+                from + self.period * tick as u32,
+                RecordedTrace {
+                    pid: thread.pid,
+                    thread_id: thread.tid,
+                    thread_name: thread.name.clone(),
+                    os_thread_id: None,
+                    active: true,
+                    owns_gil: false,
+                    frames: stack.iter().rev().cloned().collect(),
+                },
+            ));
+        }
+    }
+
+    // An empty stack so that whatever the thread was last running is seen to finish.
+    fn push_end(&mut self, thread: &ThreadKey, at: Duration) {
+        self.samples.push((
+            at,
+            RecordedTrace {
+                pid: thread.pid,
+                thread_id: thread.tid,
+                thread_name: thread.name.clone(),
+                os_thread_id: None,
+                active: false,
+                owns_gil: false,
+                frames: Vec::new(),
+            },
+        ));
+    }
+
+    fn into_samples(mut self) -> Vec<RecordedSample> {
+        self.samples.sort_by_key(|(at, _)| *at);
+        self.samples
+            .into_iter()
+            .map(|(elapsed, trace)| RecordedSample {
+                elapsed,
+                traces: vec![trace],
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ThreadKey {
+    pid: Pid,
+    tid: u64,
+    name: Option<String>,
+}
+
+impl Default for ThreadKey {
+    fn default() -> Self {
+        Self {
+            pid: 0,
+            tid: 1,
+            name: None,
+        }
+    }
+}
+
+// py-spy writes frames as `name (filename:line)`, or `name (filename)` without line numbers.
+fn parse_folded_frame(frame: &str) -> RecordedFrame {
+    let (name, location) = match frame.strip_suffix(')').and_then(|f| f.rsplit_once(" (")) {
+        Some((name, location)) => (name, location),
+        None => (frame, ""),
+    };
+    let (filename, line) = match location.rsplit_once(':') {
+        Some((filename, line)) if line.parse::<i32>().is_ok() => {
+            (filename, line.parse().unwrap_or_default())
+        }
+        _ => (location, 0),
+    };
+    RecordedFrame {
+        name: name.to_string(),
+        filename: filename.to_string(),
+        module: None,
+        short_filename: None,
+        line,
+        locals: None,
+        is_entry: false,
+    }
+}
+
+/// Reads `py-spy record --format raw` output, i.e. folded stacks with sample counts.
+pub fn read_folded<R: BufRead>(
+    reader: R,
+    sampling_rate: u64,
+) -> Result<Vec<RecordedSample>, Error> {
+    let mut clock = SyntheticClock::new(sampling_rate);
+    // every thread runs from zero on a clock of its own, as they were sampled side by side
+    let mut threads: Vec<(ThreadKey, Duration)> = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (stack, count) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| anyhow!("missing sample count in folded line '{}'", line))?;
+        let count: u64 = count.parse()?;
+
+        let mut thread = ThreadKey::default();
+        let mut frames = Vec::new();
+        for frame in stack.split(';') {
+            // with --subprocesses and --threads, py-spy prefixes the process and thread
+            if let Some(process) = frame.strip_prefix("process ") {
+                thread.pid = process
+                    .split(':')
+                    .next()
+                    .and_then(|pid| pid.parse().ok())
+                    .unwrap_or_default();
+            } else if let Some(tid) = frame
+                .strip_prefix("thread (0x")
+                .and_then(|tid| tid.strip_suffix(')'))
+            {
+                thread.tid = u64::from_str_radix(tid, 16)?;
+            } else {
+                frames.push(parse_folded_frame(frame));
+            }
+        }
+
+        let lasting = clock.period * count as u32;
+        let idx = match threads.iter().position(|(known, _)| *known == thread) {
+            Some(idx) => idx,
+            None => {
+                threads.push((thread, Duration::ZERO));
+                threads.len() - 1
+            }
+        };
+        let (thread, now) = &mut threads[idx];
+        clock.push_stack(thread, &frames, *now, lasting);
+        *now += lasting;
+    }
+
+    for (thread, now) in threads.iter() {
+        clock.push_end(thread, *now);
+    }
+    Ok(clock.into_samples())
+}
+
+#[derive(Deserialize)]
+struct SpeedscopeFile {
+    shared: SpeedscopeShared,
+    profiles: Vec<SpeedscopeProfile>,
+}
+
+#[derive(Deserialize)]
+struct SpeedscopeShared {
+    frames: Vec<SpeedscopeFrame>,
+}
+
+#[derive(Deserialize)]
+struct SpeedscopeFrame {
+    name: String,
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default)]
+    line: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SpeedscopeProfile {
+    Sampled {
+        name: String,
+        unit: String,
+        #[serde(rename = "startValue")]
+        start_value: f64,
+        samples: Vec<Vec<usize>>,
+        weights: Vec<f64>,
+    },
+    Evented {
+        name: String,
+        unit: String,
+        #[serde(rename = "startValue")]
+        start_value: f64,
+        events: Vec<SpeedscopeEvent>,
+    },
+}
+
+#[derive(Deserialize)]
+struct SpeedscopeEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    at: f64,
+    frame: usize,
+}
+
+fn speedscope_duration(value: f64, unit: &str, period: Duration) -> Result<Duration, Error> {
+    let value = value.max(0.0);
+    Ok(match unit {
+        "seconds" => Duration::from_secs_f64(value),
+        "milliseconds" => Duration::from_secs_f64(value / 1e3),
+        "microseconds" => Duration::from_secs_f64(value / 1e6),
+        "nanoseconds" => Duration::from_secs_f64(value / 1e9),
+        // unitless weights are sample counts
+        "none" => period.mul_f64(value),
+        _ => bail!("unsupported speedscope unit '{}'", unit),
+    })
+}
+
+/// Reads a speedscope file, treating each of its profiles as a thread.
+pub fn read_speedscope<R: Read>(
+    reader: R,
+    sampling_rate: u64,
+) -> Result<Vec<RecordedSample>, Error> {
+    let file: SpeedscopeFile = serde_json::from_reader(reader)?;
+    let frames: Vec<RecordedFrame> = file
+        .shared
+        .frames
+        .into_iter()
+        .map(|frame| RecordedFrame {
+            name: frame.name,
+            filename: frame.file.unwrap_or_default(),
+            module: None,
+            short_filename: None,
+            line: frame.line.unwrap_or_default(),
+            locals: None,
+            is_entry: false,
+        })
+        .collect();
+    let frame = |idx: usize| {
+        frames
+            .get(idx)
+            .cloned()
+            .ok_or_else(|| anyhow!("speedscope frame {} does not exist", idx))
+    };
+
+    let mut clock = SyntheticClock::new(sampling_rate);
+    let period = clock.period;
+    // everything is shifted so that the earliest profile starts at zero
+    let mut earliest = Duration::MAX;
+
+    for (idx, profile) in file.profiles.into_iter().enumerate() {
+        match profile {
+            SpeedscopeProfile::Sampled {
+                name,
+                unit,
+                start_value,
+                samples,
+                weights,
+            } => {
+                let thread = ThreadKey {
+                    pid: 0,
+                    tid: idx as u64 + 1,
+                    name: Some(name),
+                };
+                let mut now = speedscope_duration(start_value, &unit, period)?;
+                earliest = earliest.min(now);
+                for (stack, weight) in samples.iter().zip(weights.iter()) {
+                    let stack = stack
+                        .iter()
+                        .map(|idx| frame(*idx))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let lasting = speedscope_duration(*weight, &unit, period)?;
+                    clock.push_stack(&thread, &stack, now, lasting);
+                    now += lasting;
+                }
+                clock.push_end(&thread, now);
+            }
+            SpeedscopeProfile::Evented {
+                name,
+                unit,
+                start_value,
+                events,
+            } => {
+                let thread = ThreadKey {
+                    pid: 0,
+                    tid: idx as u64 + 1,
+                    name: Some(name),
+                };
+                let mut stack = Vec::new();
+                let mut since = speedscope_duration(start_value, &unit, period)?;
+                earliest = earliest.min(since);
+                for event in events {
+                    let at = speedscope_duration(event.at, &unit, period)?;
+                    if at > since && !stack.is_empty() {
+                        clock.push_stack(&thread, &stack, since, at - since);
+                    }
+                    since = since.max(at);
+                    match event.kind.as_str() {
+                        "O" => stack.push(frame(event.frame)?),
+                        "C" => {
+                            stack.pop();
+                        }
+                        kind => bail!("unknown speedscope event type '{}'", kind),
+                    }
+                }
+                clock.push_end(&thread, since);
+            }
+        }
+    }
+
+    if earliest != Duration::MAX {
+        for (at, _) in clock.samples.iter_mut() {
+            *at -= earliest;
+        }
+    }
+    Ok(clock.into_samples())
+}
+
+/// Reads either a speedscope or a folded file, telling them apart by their first character.
+pub fn import_samples<P: AsRef<Path>>(
+    path: P,
+    sampling_rate: u64,
+) -> Result<Vec<RecordedSample>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let is_json = reader
+        .fill_buf()?
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|b| *b == b'{');
+    if is_json {
+        read_speedscope(reader, sampling_rate)
+    } else {
+        read_folded(reader, sampling_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(sample: &RecordedSample) -> Vec<&str> {
+        sample.traces[0]
+            .frames
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_read_folded() {
+        let folded = "main (a.py:1);work (b.py:2) 2\nthread (0x10);main (a.py) 1\n";
+        let samples = read_folded(folded.as_bytes(), 10).unwrap();
+
+        assert_eq!(samples.len(), 5);
+        assert_eq!(names(&samples[0]), vec!["work", "main"]);
+        assert_eq!(samples[0].traces[0].frames[0].filename, "b.py");
+        assert_eq!(samples[0].traces[0].frames[0].line, 2);
+        // the threads ran side by side from the start
+        assert_eq!(samples[1].elapsed, Duration::ZERO);
+        assert_eq!(samples[1].traces[0].thread_id, 0x10);
+        assert_eq!(samples[1].traces[0].frames[0].line, 0);
+        assert_eq!(samples[2].elapsed, Duration::from_millis(100));
+        // each thread finishes one period after its own last sample
+        let ends = samples
+            .iter()
+            .filter(|s| s.traces[0].frames.is_empty())
+            .map(|s| (s.traces[0].thread_id, s.elapsed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ends,
+            vec![
+                (0x10, Duration::from_millis(100)),
+                (1, Duration::from_millis(200))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_speedscope() {
+        let speedscope = r#"{
+            "shared": {"frames": [{"name": "main", "file": "a.py"}, {"name": "work"}]},
+            "profiles": [
+                {"type": "sampled", "name": "t", "unit": "seconds", "startValue": 1.0,
+                 "samples": [[0, 1], [0]], "weights": [0.2, 0.1]},
+                {"type": "evented", "name": "e", "unit": "milliseconds", "startValue": 1000.0,
+                 "events": [{"type": "O", "frame": 0, "at": 1000.0},
+                            {"type": "C", "frame": 0, "at": 1100.0}]}
+            ]
+        }"#;
+        let samples = read_speedscope(speedscope.as_bytes(), 10).unwrap();
+
+        let sampled: Vec<_> = samples
+            .iter()
+            .filter(|s| s.traces[0].thread_id == 1)
+            .collect();
+        assert_eq!(sampled.len(), 4);
+        assert_eq!(sampled[0].elapsed, Duration::ZERO);
+        assert_eq!(names(sampled[0]), vec!["work", "main"]);
+        assert_eq!(names(sampled[2]), vec!["main"]);
+        assert_eq!(sampled[3].elapsed, Duration::from_millis(300));
+
+        let evented: Vec<_> = samples
+            .iter()
+            .filter(|s| s.traces[0].thread_id == 2)
+            .collect();
+        assert_eq!(evented.len(), 2);
+        assert_eq!(evented[0].traces[0].thread_name.as_deref(), Some("e"));
+        assert_eq!(evented[1].elapsed, Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Error, anyhow, bail};
+use py_spy::sampler::Sampler;
+use remoteprocess::Pid;
+
+// How long a freshly spawned process gets to bring up its interpreter.
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+const ATTACH_RETRY: Duration = Duration::from_millis(10);
+
+/// A python process started by fadetop, e.g. `fadetop -- python train.py`.
+#[derive(Debug)]
+pub struct LaunchedProcess {
+    child: Child,
+    pub command: String,
+    pub exit_status: Option<ExitStatus>,
+}
+
+impl LaunchedProcess {
+    /// Spawns `command`, sending its stdout and stderr to `output`.
+    pub fn spawn<P: AsRef<Path>>(command: &[String], output: P) -> Result<Self, Error> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| anyhow!("no command to launch"))?;
+        let output = File::create(output)?;
+        let child = Command::new(program)
+            .args(args)
+            // the terminal's input belongs to fadetop
+            .stdin(Stdio::null())
+            .stdout(output.try_clone()?)
+            .stderr(output)
+            .spawn()?;
+        Ok(Self {
+            child,
+            command: command.join(" "),
+            exit_status: None,
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.child.id() as Pid
+    }
+
+    /// Attaches py-spy as soon as the interpreter is far enough along to be sampled.
+    pub fn attach(&mut self, config: &py_spy::Config) -> Result<Sampler, Error> {
+        let started = Instant::now();
+        loop {
+            match Sampler::new(self.pid(), config) {
+                Ok(sampler) => return Ok(sampler),
+                Err(err) => {
+                    if let Some(status) = self.poll() {
+                        bail!(
+                            "{} exited with {} before it could be sampled",
+                            self.command,
+                            status
+                        );
+                    }
+                    if started.elapsed() > ATTACH_TIMEOUT {
+                        self.kill()?;
+                        return Err(err);
+                    }
+                }
+            }
+            thread::sleep(ATTACH_RETRY);
+        }
+    }
+
+    /// Checks whether the process has exited, without blocking.
+    pub fn poll(&mut self) -> Option<ExitStatus> {
+        if self.exit_status.is_none() {
+            self.exit_status = self.child.try_wait().ok().flatten();
+        }
+        self.exit_status
+    }
+
+    /// Asks the process to exit with SIGTERM, so that it gets to clean up, and waits
+    /// for it to do so unless it has already exited.
+    pub fn terminate(&mut self) -> Result<ExitStatus, Error> {
+        if let Some(status) = self.poll() {
+            return Ok(status);
+        }
+        // SAFETY: only sends a signal to our own child, which has not been reaped yet
+        if unsafe { libc::kill(self.pid(), libc::SIGTERM) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let status = self.child.wait()?;
+        self.exit_status = Some(status);
+        Ok(status)
+    }
+
+    /// Kills the process unless it has already exited.
+    pub fn kill(&mut self) -> Result<(), Error> {
+        if self.poll().is_none() {
+            self.child.kill()?;
+            self.exit_status = Some(self.child.wait()?);
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,19 @@
 pub mod app;
+pub mod clock;
 pub mod config;
+pub mod control;
+pub mod discover;
 pub mod errors;
+pub mod export;
+pub mod health;
+pub mod import;
+pub mod launch;
 pub mod priority;
+pub mod record;
+pub mod replay;
 pub mod ser;
 mod state;
 pub mod tabs;
+#[cfg(test)]
+mod testing;
+pub mod watch;
@@ -1,14 +1,85 @@
-use anyhow::Error;
-use clap::{CommandFactory, FromArgMatches, Parser, command};
-use fadetop::{app::FadeTopApp, config::AppConfig};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-use py_spy;
+use anyhow::{Error, bail};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use fadetop::{
+    app::{FadeTopApp, LiveSampler, pick_python_process},
+    config::AppConfig,
+    discover::resolve_pids,
+    export::ExportFormat,
+    launch::LaunchedProcess,
+    record::SampleRecorder,
+    replay::ReplaySampler,
+    watch::{WatchTarget, WatchingSampler},
+};
+
+use regex::Regex;
 use remoteprocess::Pid;
 
 #[derive(Parser, Debug)]
 #[command(version)]
 struct Args {
-    pid: Pid,
+    /// Pids of the python processes to attach to, picked interactively if omitted
+    pid: Vec<Pid>,
+    /// Attach to every python process whose command line matches this regex instead of pids
+    #[arg(long, visible_alias = "pgrep", conflicts_with_all = ["pid", "replay", "import", "command"])]
+    name: Option<Regex>,
+    /// Wait for a process matching --name to appear instead of failing
+    #[arg(long, requires = "name")]
+    wait: bool,
+    /// Keep the history when the process exits and attach to its restarted successor,
+    /// recognised by --name or else by the same command line
+    #[arg(long, conflicts_with_all = ["replay", "import", "command"])]
+    watch: bool,
+    /// Record every sample taken into this file, when following a single process
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Replay a file written by --record instead of attaching to a process
+    #[arg(long, conflicts_with_all = ["pid", "import"])]
+    replay: Option<PathBuf>,
+    /// Browse a py-spy raw (folded) or speedscope file instead of attaching to a process
+    #[arg(long, conflicts_with_all = ["pid", "record"])]
+    import: Option<PathBuf>,
+    /// Sampling rate the stacks of an imported folded file are laid out at [default: sampling_rate]
+    #[arg(long, requires = "import")]
+    import_rate: Option<u64>,
+    /// Export the timeline as Chrome trace JSON to this file on exit
+    #[arg(long)]
+    chrome_trace: Option<PathBuf>,
+    /// Export the timeline as a speedscope profile to this file on exit
+    #[arg(long)]
+    speedscope: Option<PathBuf>,
+    /// Where folded stacks of the visible window are written when f/F is pressed
+    #[arg(long)]
+    folded: Option<PathBuf>,
+    /// Where the stdout and stderr of a launched command are written
+    #[arg(long, default_value = "fadetop_output.log", requires = "command")]
+    output: PathBuf,
+    /// Launch this python command and attach to it, e.g. `fadetop -- python train.py`.
+    /// Quitting fadetop sends it SIGTERM and waits for it to exit
+    #[arg(last = true, conflicts_with_all = ["pid", "replay", "import"])]
+    command: Vec<String>,
+}
+
+impl Args {
+    fn app(&self, configs: AppConfig) -> FadeTopApp {
+        let mut app = FadeTopApp::new(configs);
+        for (format, path) in [
+            (ExportFormat::ChromeTrace, &self.chrome_trace),
+            (ExportFormat::Speedscope, &self.speedscope),
+        ] {
+            if let Some(path) = path {
+                app = app.with_export(format, path.clone());
+            }
+        }
+        if let Some(path) = &self.folded {
+            app = app.with_folded_path(path.clone());
+        }
+        app
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -20,25 +91,109 @@ async fn main() -> Result<(), Error> {
 
     let args = Args::from_arg_matches_mut(&mut cmd.try_get_matches()?)?;
 
+    let replay = match (&args.replay, &args.import) {
+        (Some(path), _) => Some(ReplaySampler::open(path)?),
+        (None, Some(path)) => Some(ReplaySampler::import(
+            path,
+            args.import_rate.unwrap_or(configs.sampling_rate),
+        )?),
+        (None, None) => None,
+    };
+    if let Some(sampler) = replay {
+        let terminal = ratatui::init();
+        let app = args
+            .app(configs.clone())
+            .with_replay_control(sampler.control());
+        let result = app.run(terminal, sampler).await;
+        ratatui::restore();
+        return result;
+    }
+
+    let py_spy_config = py_spy::Config {
+        blocking: configs.locking_strategy.clone(),
+        sampling_rate: configs.sampling_rate,
+        subprocesses: configs.subprocesses,
+        native: configs.native,
+        dump_locals: configs.dump_locals,
+        ..Default::default()
+    };
+    let (samplers, launched) = if args.command.is_empty() {
+        let pids = match (args.pid.as_slice(), &args.name) {
+            ([], Some(pattern)) => resolve_pids(pattern, args.wait)?,
+            ([], None) => {
+                let mut terminal = ratatui::init();
+                let picked = pick_python_process(&mut terminal);
+                ratatui::restore();
+                match picked? {
+                    Some(pid) => vec![pid],
+                    None => return Ok(()),
+                }
+            }
+            (pids, _) => pids.to_vec(),
+        };
+        let samplers = pids
+            .into_iter()
+            .map(|pid| {
+                let sampler = py_spy::sampler::Sampler::new(pid, &py_spy_config)?;
+                Ok(LiveSampler::new(sampler, pid, py_spy_config.clone()))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        (samplers, None)
+    } else {
+        let mut launched = LaunchedProcess::spawn(&args.command, &args.output)?;
+        let sampler = launched.attach(&py_spy_config)?;
+        let live = LiveSampler::new(sampler, launched.pid(), py_spy_config.clone());
+        (vec![live], Some(Arc::new(Mutex::new(launched))))
+    };
+    if args.record.is_some() && samplers.len() > 1 {
+        bail!("--record can only follow a single process");
+    }
+    let mut recorder = args
+        .record
+        .as_ref()
+        .map(SampleRecorder::create)
+        .transpose()?;
+    let samplers = samplers
+        .into_iter()
+        .map(|live| match recorder.take() {
+            Some(recorder) => live.with_recorder(recorder),
+            None => live,
+        })
+        .collect::<Vec<_>>();
+    let watched = if args.watch {
+        let watching = samplers
+            .into_iter()
+            .map(|live| {
+                let target = match &args.name {
+                    Some(pattern) => WatchTarget::Pattern(pattern.clone()),
+                    None => WatchTarget::same_as(live.pid())?,
+                };
+                Ok(WatchingSampler::new(live, target))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(watching)
+    } else {
+        Err(samplers)
+    };
+
     let terminal = ratatui::init();
-    let app = FadeTopApp::new(configs.clone());
-
-    let result = app
-        .run(
-            terminal,
-            py_spy::sampler::Sampler::new(
-                args.pid,
-                &py_spy::Config {
-                    blocking: configs.locking_strategy,
-                    sampling_rate: configs.sampling_rate,
-                    subprocesses: configs.subprocesses,
-                    native: configs.native,
-                    dump_locals: configs.dump_locals,
-                    ..Default::default()
-                },
-            )?,
-        )
-        .await;
+    let mut app = args.app(configs.clone());
+    if let Some(launched) = &launched {
+        app = app.with_launched(Arc::clone(launched));
+    }
+
+    let result = match watched {
+        Ok(watching) => app.run(terminal, watching).await,
+        Err(samplers) => app.run(terminal, samplers).await,
+    };
     ratatui::restore();
+
+    if let Some(Ok(mut launched)) = launched.as_ref().map(|l| l.lock()) {
+        if launched.poll().is_none() {
+            eprintln!("waiting for {} to exit on SIGTERM", launched.command);
+        }
+        let status = launched.terminate()?;
+        eprintln!("{} exited with {}", launched.command, status);
+    }
     result
 }
@@ -1,17 +1,26 @@
+use py_spy::sampler::Sample;
 use py_spy::stack_trace::Frame;
 use py_spy::stack_trace::LocalVariable;
 use py_spy::stack_trace::StackTrace;
+use regex::Regex;
 use remoteprocess::{Pid, Tid};
 use serde::Deserialize;
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::hash_map::Iter;
 use std::collections::hash_map::Keys;
+use std::ops::{Bound, Range};
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 
-use crate::ser::parse_duration;
+use crate::clock::{Clock, system_clock};
+use crate::health::SamplerHealth;
+use crate::ser::{parse_duration, parse_glob, parse_optional_duration, parse_regex, parse_size};
 
 #[derive(Debug, Clone, Default)]
 pub struct ThreadInfo {
@@ -20,10 +29,41 @@ pub struct ThreadInfo {
     pub tid: Tid,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct FrameKey {
     filename: String,
     pub name: String,
+    module: Option<String>,
+    /// The dotted name the frame's file was imported under, e.g. `torch.nn.linear`.
+    python_module: Option<String>,
+}
+
+impl From<&Frame> for FrameKey {
+    fn from(frame: &Frame) -> Self {
+        Self {
+            filename: frame.filename.clone(),
+            name: frame.name.clone(),
+            module: frame.module.clone(),
+            python_module: python_module(frame),
+        }
+    }
+}
+
+/// Tells the module a Python file was imported as from its path relative to `sys.path`,
+/// which py-spy gives where it can, or else below `site-packages`, or else by its name alone.
+fn python_module(frame: &Frame) -> Option<String> {
+    let path = frame.short_filename.as_deref().unwrap_or(&frame.filename);
+    let path = ["site-packages/", "dist-packages/"]
+        .iter()
+        .find_map(|dir| path.rsplit_once(dir))
+        .map_or(path, |(_, below)| below);
+    let path = path.strip_suffix(".py")?;
+    let path = match path.strip_prefix('/') {
+        Some(_) => path.rsplit('/').next()?,
+        None => path.trim_start_matches("./"),
+    };
+    let path = path.strip_suffix("/__init__").unwrap_or(path);
+    Some(path.replace('/', "."))
 }
 
 impl FrameKey {
@@ -34,6 +74,79 @@ impl FrameKey {
     pub fn fqn(&self) -> String {
         format!("{}::{}", self.filename, self.name)
     }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn module(&self) -> Option<&str> {
+        self.module.as_deref()
+    }
+
+    /// Whether the frame's file is the Python module `module` or one of its submodules.
+    pub fn in_module(&self, module: &str) -> bool {
+        self.python_module.as_deref().is_some_and(|name| {
+            name.strip_prefix(module)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    }
+}
+
+/// What the thread was doing while a frame was on its stack.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Activity {
+    Busy,
+    /// Running, but mostly without holding the GIL.
+    WithoutGil,
+    Idle,
+}
+
+/// How many samples saw a frame, and in how many of them its thread was running or held the GIL.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SampleCounts {
+    pub samples: u64,
+    pub active: u64,
+    pub owns_gil: u64,
+}
+
+impl SampleCounts {
+    fn merge(&mut self, other: &SampleCounts) {
+        self.samples += other.samples;
+        self.active += other.active;
+        self.owns_gil += other.owns_gil;
+    }
+
+    fn add(&mut self, trace: &StackTrace) {
+        self.samples += 1;
+        self.active += trace.active as u64;
+        self.owns_gil += trace.owns_gil as u64;
+    }
+
+    fn of(trace: &StackTrace) -> Self {
+        let mut counts = Self::default();
+        counts.add(trace);
+        counts
+    }
+
+    pub fn activity(&self) -> Activity {
+        if self.active * 2 < self.samples {
+            Activity::Idle
+        } else if self.owns_gil * 2 < self.samples {
+            Activity::WithoutGil
+        } else {
+            Activity::Busy
+        }
+    }
+}
+
+/// What a record made of many short ones merged together stands for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Aggregate {
+    /// How many records were merged, `calls` of which were of the record's own frame.
+    pub records: u64,
+    pub calls: u64,
+    /// How long the record's own frame ran for in total.
+    pub busy: Duration,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -42,12 +155,25 @@ pub struct FinishedRecord {
     pub start: Instant,
     pub end: Instant,
     pub depth: usize,
+    pub counts: SampleCounts,
+    pub aggregate: Option<Aggregate>,
+    /// Kept no matter what the rules say, as it or one of its descendants was pinned.
+    pub pinned: bool,
     forget_time: ForgetTime,
+    // tells apart records of one depth that start at the same instant
+    id: u64,
 }
 
 impl Ord for FinishedRecord {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        Reverse(self.forget_time).cmp(&Reverse(other.forget_time))
+        // pinned records go last, even after those the rules keep forever, and of
+        // those due at once the oldest go first
+        Reverse((self.forget_time, self.pinned, self.end, self.id)).cmp(&Reverse((
+            other.forget_time,
+            other.pinned,
+            other.end,
+            other.id,
+        )))
     }
 }
 
@@ -61,6 +187,9 @@ impl PartialOrd for FinishedRecord {
 pub struct UnfinishedRecord {
     pub frame_key: FrameKey,
     pub start: Instant,
+    pub counts: SampleCounts,
+    // set once a descendant is pinned, so the record is pinned too when it finishes
+    pinned: bool,
     locals: Option<Vec<LocalVariable>>,
 }
 
@@ -70,29 +199,339 @@ impl UnfinishedRecord {
     }
 }
 
+fn key_bytes(key: &FrameKey) -> usize {
+    key.name.capacity()
+        + key.filename.capacity()
+        + key.module.as_ref().map_or(0, String::capacity)
+        + key.python_module.as_ref().map_or(0, String::capacity)
+}
+
+// Roughly what keeping a finished record costs, counting it in both the heap and the index.
+fn record_bytes(record: &FinishedRecord) -> usize {
+    size_of::<FinishedRecord>()
+        + 2 * size_of::<Arc<FinishedRecord>>()
+        + key_bytes(&record.frame_key)
+}
+
+/// Finished records of one thread by depth, then by when they started.
+///
+/// Records at one depth never overlap, so those within a window of time are a contiguous run.
+#[derive(Clone, Debug, Default)]
+struct RecordIndex {
+    by_depth: Vec<BTreeMap<(Instant, u64), Arc<FinishedRecord>>>,
+    bytes: usize,
+}
+
+impl RecordIndex {
+    fn insert(&mut self, record: Arc<FinishedRecord>) {
+        if self.by_depth.len() <= record.depth {
+            self.by_depth.resize_with(record.depth + 1, BTreeMap::new);
+        }
+        self.bytes += record_bytes(&record);
+        self.by_depth[record.depth].insert((record.start, record.id), record);
+    }
+
+    fn remove(&mut self, record: &FinishedRecord) {
+        if let Some(records) = self.by_depth.get_mut(record.depth)
+            && records.remove(&(record.start, record.id)).is_some()
+        {
+            self.bytes -= record_bytes(record);
+        }
+        while self.by_depth.last().is_some_and(BTreeMap::is_empty) {
+            self.by_depth.pop();
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SpiedRecordQueue {
     pub unfinished_events: Vec<UnfinishedRecord>,
-    pub finished_events: BinaryHeap<FinishedRecord>,
+    // in the order they are to be forgotten, sharing the records with `index`
+    finished_events: BinaryHeap<Arc<FinishedRecord>>,
+    index: RecordIndex,
+    next_id: u64,
+    pinned: Vec<Arc<FinishedRecord>>,
     pub start_ts: Instant,
     pub last_update: Instant,
     pub thread_info: ThreadInfo,
+    /// When the thread was last seen running rather than idle.
+    pub last_active: Option<Instant>,
+    /// Stretches of time in which the sampler stalled, so nothing is known.
+    pub gaps: Vec<Range<Instant>>,
 }
 
 impl SpiedRecordQueue {
-    fn new(thread_info: ThreadInfo) -> Self {
+    fn new(thread_info: ThreadInfo, at: Instant) -> Self {
         SpiedRecordQueue {
             finished_events: BinaryHeap::new(),
+            index: RecordIndex::default(),
+            next_id: 0,
+            pinned: Vec::new(),
             unfinished_events: vec![],
-            start_ts: Instant::now(),
-            last_update: Instant::now(),
+            start_ts: at,
+            last_update: at,
             thread_info,
+            last_active: None,
+            gaps: Vec::new(),
         }
     }
 
-    pub fn thread_name<'a>(&'a self) -> &'a Option<String> {
+    pub fn thread_name(&self) -> &Option<String> {
         &self.thread_info.name
     }
+
+    // The first gap the thread has not been sampled since, if any.
+    fn gap_since_update(&self) -> Option<&Range<Instant>> {
+        self.gaps
+            .iter()
+            .rev()
+            .take_while(|gap| gap.start >= self.last_update)
+            .last()
+    }
+
+    // Gaps that ended before the oldest record kept are nowhere to be drawn any more.
+    fn prune_gaps(&mut self) {
+        if self.gaps.is_empty() {
+            return;
+        }
+        let oldest = self
+            .index
+            .by_depth
+            .iter()
+            .filter_map(|records| records.first_key_value().map(|((start, _), _)| *start))
+            .chain(self.unfinished_events.first().map(|record| record.start))
+            .min();
+        let stale = oldest.map_or(self.gaps.len(), |oldest| {
+            self.gaps.partition_point(|gap| gap.end <= oldest)
+        });
+        self.gaps.drain(..stale);
+    }
+
+    fn finish(&mut self, record: FinishedRecord) -> Arc<FinishedRecord> {
+        let record = Arc::new(FinishedRecord {
+            id: self.next_id,
+            ..record
+        });
+        self.next_id += 1;
+        self.index.insert(Arc::clone(&record));
+        self.finished_events.push(Arc::clone(&record));
+        record
+    }
+
+    fn finished_arc(&self, depth: usize, start: Instant) -> Option<&Arc<FinishedRecord>> {
+        self.index
+            .by_depth
+            .get(depth)?
+            .range((start, 0)..=(start, u64::MAX))
+            .next()
+            .map(|(_, record)| record)
+    }
+
+    /// The finished record at `depth` that started at `start`.
+    pub fn finished_at(&self, depth: usize, start: Instant) -> Option<&FinishedRecord> {
+        self.finished_arc(depth, start).map(AsRef::as_ref)
+    }
+
+    /// The latest finished record at `depth` that started before `before`.
+    pub fn finished_before(&self, depth: usize, before: Instant) -> Option<&FinishedRecord> {
+        self.index
+            .by_depth
+            .get(depth)?
+            .range(..(before, 0))
+            .next_back()
+            .map(|(_, record)| record.as_ref())
+    }
+
+    /// The earliest finished record at `depth` that started after `after`.
+    pub fn finished_after(&self, depth: usize, after: Instant) -> Option<&FinishedRecord> {
+        self.index
+            .by_depth
+            .get(depth)?
+            .range((Bound::Excluded((after, u64::MAX)), Bound::Unbounded))
+            .next()
+            .map(|(_, record)| record.as_ref())
+    }
+
+    /// Keeps the finished record at `depth` that started at `start` forever, along with
+    /// the frames it was called from. Returns whether it was not pinned already.
+    fn pin(&mut self, depth: usize, start: Instant) -> bool {
+        let Some(record) = self.finished_arc(depth, start).cloned() else {
+            return false;
+        };
+        if self.pinned.iter().any(|pinned| pinned.id == record.id) {
+            return false;
+        }
+        let end = record.end;
+        let ancestors = (0..depth)
+            .filter_map(|depth| {
+                self.index
+                    .by_depth
+                    .get(depth)?
+                    .range(..=(start, u64::MAX))
+                    .next_back()
+                    .map(|(_, ancestor)| ancestor)
+                    .filter(|ancestor| ancestor.end >= end)
+                    .cloned()
+            })
+            .collect::<Vec<_>>();
+        for ancestor in ancestors {
+            if !ancestor.pinned {
+                self.repin(&ancestor);
+            }
+        }
+        for unfinished in self.unfinished_events.iter_mut().take(depth) {
+            if unfinished.start <= start {
+                unfinished.pinned = true;
+            }
+        }
+        let pinned = if record.pinned {
+            record
+        } else {
+            self.repin(&record)
+        };
+        self.pinned.push(pinned);
+        true
+    }
+
+    // Swaps `record` for a pinned copy of it.
+    fn repin(&mut self, record: &Arc<FinishedRecord>) -> Arc<FinishedRecord> {
+        self.index.remove(record);
+        self.finished_events.retain(|other| other.id != record.id);
+        self.finish(FinishedRecord {
+            pinned: true,
+            forget_time: ForgetTime::Never,
+            ..FinishedRecord::clone(record)
+        })
+    }
+
+    /// The records pinned on purpose, as opposed to along with a descendant.
+    pub fn pinned(&self) -> &[Arc<FinishedRecord>] {
+        &self.pinned
+    }
+
+    fn pop_finished(&mut self) -> Option<Arc<FinishedRecord>> {
+        let record = self.finished_events.pop()?;
+        self.index.remove(&record);
+        Some(record)
+    }
+
+    pub fn finished_len(&self) -> usize {
+        self.finished_events.len()
+    }
+
+    /// Approximately how much memory the finished records take.
+    pub fn finished_bytes(&self) -> usize {
+        self.index.bytes
+    }
+
+    /// Every finished record, by depth and then by start.
+    pub fn finished(&self) -> impl Iterator<Item = &FinishedRecord> {
+        self.index
+            .by_depth
+            .iter()
+            .flat_map(|records| records.values().map(AsRef::as_ref))
+    }
+
+    /// The finished records at `depths` that overlap `left..right`, latest first within a depth.
+    pub fn finished_in(
+        &self,
+        depths: Range<usize>,
+        left: Instant,
+        right: Instant,
+    ) -> impl Iterator<Item = &FinishedRecord> {
+        let depths =
+            depths.start.min(self.index.by_depth.len())..depths.end.min(self.index.by_depth.len());
+        self.index.by_depth[depths].iter().flat_map(move |records| {
+            records
+                .range(..(right, 0))
+                .rev()
+                .map(|(_, record)| record.as_ref())
+                .take_while(move |record| record.end > left)
+        })
+    }
+
+    // When the record one level up from `depth` that was running at `at` started,
+    // which tells records with different parents apart.
+    fn parent_start(&self, depth: usize, at: Instant) -> Option<Instant> {
+        let depth = depth.checked_sub(1)?;
+        let finished = self
+            .index
+            .by_depth
+            .get(depth)
+            .and_then(|records| records.range(..=(at, u64::MAX)).next_back())
+            .map(|((start, _), _)| *start);
+        let unfinished = self
+            .unfinished_events
+            .get(depth)
+            .map(|record| record.start)
+            .filter(|start| *start <= at);
+        finished.max(unfinished)
+    }
+
+    /// Merges runs of records shorter than `shorter_than` that lie within `window`,
+    /// never merging across buckets or records with different parents.
+    fn compact(
+        &mut self,
+        window: Range<Instant>,
+        shorter_than: Duration,
+        bucket_of: impl Fn(Instant) -> u128,
+        rules: &[ForgetRules],
+    ) {
+        let mut merged = Vec::new();
+        for (depth, records) in self.index.by_depth.iter().enumerate() {
+            let mut run: Vec<Arc<FinishedRecord>> = Vec::new();
+            for record in records
+                .range((window.start, 0)..(window.end, 0))
+                .map(|(_, record)| record)
+            {
+                let mergeable = !record.pinned
+                    && record.end <= window.end
+                    && record.end - record.start < shorter_than;
+                if !mergeable
+                    || run.last().is_some_and(|last| {
+                        bucket_of(last.start) != bucket_of(record.start)
+                            || self.parent_start(depth, last.start)
+                                != self.parent_start(depth, record.start)
+                    })
+                {
+                    if run.len() > 1 {
+                        merged.push(std::mem::take(&mut run));
+                    }
+                    run.clear();
+                }
+                if mergeable {
+                    run.push(Arc::clone(record));
+                }
+            }
+            if run.len() > 1 {
+                merged.push(run);
+            }
+        }
+        if merged.is_empty() {
+            return;
+        }
+
+        let mut ids = HashSet::new();
+        for run in merged {
+            for record in run.iter() {
+                self.index.remove(record);
+                ids.insert(record.id);
+            }
+            self.finish(aggregate(&run, rules));
+        }
+        self.finished_events
+            .retain(|record| !ids.contains(&record.id));
+    }
+
+    pub fn max_finished_depth(&self) -> usize {
+        self.index.by_depth.len().saturating_sub(1)
+    }
+
+    /// Whether the thread has not run for a while as of its latest sample.
+    pub fn is_idle(&self) -> bool {
+        self.last_active
+            .is_none_or(|at| self.last_update.saturating_duration_since(at) > IDLE_AFTER)
+    }
 }
 
 fn event(
@@ -100,6 +539,7 @@ fn event(
     start: Instant,
     end: Instant,
     depth: usize,
+    counts: SampleCounts,
     forget_time: ForgetTime,
 ) -> FinishedRecord {
     FinishedRecord {
@@ -107,7 +547,66 @@ fn event(
         start,
         end,
         depth,
+        counts,
+        aggregate: None,
+        pinned: false,
         forget_time,
+        id: 0,
+    }
+}
+
+// Merges a run of consecutive records at one depth into a block named after the frame that
+// ran for longest in it.
+fn aggregate(run: &[Arc<FinishedRecord>], rules: &[ForgetRules]) -> FinishedRecord {
+    let mut tally: HashMap<&FrameKey, (u64, Duration)> = HashMap::new();
+    let mut counts = SampleCounts::default();
+    let mut records = 0;
+    for record in run {
+        let (calls, busy) = match record.aggregate {
+            Some(aggregate) => {
+                records += aggregate.records;
+                (aggregate.calls, aggregate.busy)
+            }
+            None => {
+                records += 1;
+                (1, record.end - record.start)
+            }
+        };
+        let entry = tally.entry(&record.frame_key).or_default();
+        entry.0 += calls;
+        entry.1 += busy;
+        counts.merge(&record.counts);
+    }
+    let (frame_key, (calls, busy)) = tally
+        .into_iter()
+        .max_by_key(|(_, (_, busy))| *busy)
+        .expect("runs are never empty");
+    let (start, end) = (run[0].start, run[run.len() - 1].end);
+    FinishedRecord {
+        frame_key: frame_key.clone(),
+        start,
+        end,
+        depth: run[0].depth,
+        counts,
+        aggregate: Some(Aggregate {
+            records,
+            calls,
+            busy,
+        }),
+        pinned: false,
+        // merging never makes anything be forgotten sooner
+        forget_time: run
+            .iter()
+            .map(|record| record.forget_time)
+            .chain([forget_time(
+                rules,
+                Some((frame_key, run[0].depth)),
+                start,
+                end,
+            )])
+            .max()
+            .unwrap_or(ForgetTime::Never),
+        id: 0,
     }
 }
 
@@ -143,6 +642,32 @@ pub enum ForgetRules {
         at_least: Duration,
         ratio: f32,
     },
+    /// Caps how many records, or roughly how many bytes of them, are kept, for each thread
+    /// or for all of them together. Over budget, records are forgotten early in the order
+    /// the other rules would have forgotten them.
+    Budget {
+        #[serde(default)]
+        per_thread: bool,
+        records: Option<usize>,
+        #[serde(default, deserialize_with = "parse_size")]
+        bytes: Option<usize>,
+    },
+    /// Decides alone for the frames it matches, which are kept `after` plus `ratio` times
+    /// their duration once they ended, or forever without an `after`. The first matching
+    /// rule wins.
+    Matching {
+        #[serde(default, deserialize_with = "parse_regex")]
+        function: Option<Regex>,
+        #[serde(default, deserialize_with = "parse_glob")]
+        filename: Option<Regex>,
+        module: Option<String>,
+        min_depth: Option<usize>,
+        max_depth: Option<usize>,
+        #[serde(default, deserialize_with = "parse_optional_duration")]
+        after: Option<Duration>,
+        #[serde(default)]
+        ratio: f32,
+    },
 }
 
 impl ForgetRules {
@@ -158,86 +683,422 @@ impl ForgetRules {
             Self::RectLinear { at_least, ratio } => {
                 ForgetTime::When(end + at_least + (end - start).mul_f32(ratio))
             }
+            Self::Budget { .. } => ForgetTime::Never,
+            Self::Matching { after, ratio, .. } => match after {
+                Some(after) => ForgetTime::When(end + after + (end - start).mul_f32(ratio)),
+                None => ForgetTime::Never,
+            },
         }
     }
 }
 
-fn forget_time(rules: &Vec<ForgetRules>, start: Instant, end: Instant) -> ForgetTime {
+/// Merges records that ended more than `older_than` ago and lasted less than `shorter_than`
+/// into one block per run of them within each `bucket` of time.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CompactionRule {
+    #[serde(deserialize_with = "parse_duration")]
+    pub older_than: Duration,
+    #[serde(deserialize_with = "parse_duration")]
+    pub shorter_than: Duration,
+    #[serde(deserialize_with = "parse_duration")]
+    pub bucket: Duration,
+}
+
+impl ForgetRules {
+    fn matches(&self, key: &FrameKey, depth: usize) -> bool {
+        match self {
+            Self::Matching {
+                function,
+                filename,
+                module,
+                min_depth,
+                max_depth,
+                ..
+            } => {
+                function.as_ref().is_none_or(|re| re.is_match(&key.name))
+                    && filename
+                        .as_ref()
+                        .is_none_or(|re| re.is_match(&key.filename))
+                    && module.as_ref().is_none_or(|module| key.in_module(module))
+                    && min_depth.is_none_or(|min| depth >= min)
+                    && max_depth.is_none_or(|max| depth <= max)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// When a frame that ran from `start` to `end` is to be forgotten, or a whole thread
+/// without a `frame`.
+fn forget_time(
+    rules: &[ForgetRules],
+    frame: Option<(&FrameKey, usize)>,
+    start: Instant,
+    end: Instant,
+) -> ForgetTime {
+    if let Some((key, depth)) = frame
+        && let Some(rule) = rules.iter().find(|rule| rule.matches(key, depth))
+    {
+        return rule.pop_time(start, end);
+    }
     rules
         .iter()
+        .filter(|rule| !matches!(rule, ForgetRules::Matching { .. }))
         .map(|rule| rule.pop_time(start, end))
         .min()
         .unwrap_or(ForgetTime::Never)
 }
 
-#[derive(Debug, Default)]
+// Samples further apart than this many sampling periods mean the sampler stalled.
+const GAP_PERIODS: u32 = 5;
+
+// A thread counts as idle once it has not run for this long, so brief waits don't make it flicker.
+const IDLE_AFTER: Duration = Duration::from_secs(1);
+
+// Thread ids are only unique within a process, and a restarted process may well reuse them.
+pub type ThreadKey = (Pid, Tid);
+
+#[derive(Debug, Clone)]
 pub struct SpiedRecordQueueMap {
-    map: HashMap<Tid, SpiedRecordQueue>,
+    map: HashMap<ThreadKey, SpiedRecordQueue>,
     rules: Vec<ForgetRules>,
+    // each rule with how far into the session it has compacted so far
+    compaction: Vec<(CompactionRule, Duration)>,
+    exited: HashSet<Pid>,
+    last_sampled: HashMap<Pid, Instant>,
+    gap_count: usize,
+    evicted: u64,
+    health: SamplerHealth,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    unix_start: SystemTime,
+}
+
+impl Default for SpiedRecordQueueMap {
+    fn default() -> Self {
+        let clock = system_clock();
+        Self {
+            map: HashMap::new(),
+            rules: Vec::new(),
+            compaction: Vec::new(),
+            exited: HashSet::new(),
+            last_sampled: HashMap::new(),
+            gap_count: 0,
+            evicted: 0,
+            health: SamplerHealth::default(),
+            start: clock.now(),
+            clock,
+            unix_start: SystemTime::now(),
+        }
+    }
 }
 
 impl SpiedRecordQueueMap {
-    pub fn keys(&self) -> Keys<'_, Tid, SpiedRecordQueue> {
+    /// Wall clock time at which this session started.
+    pub fn unix_start(&self) -> SystemTime {
+        self.unix_start
+    }
+
+    /// Time elapsed between the start of this session and `at`.
+    pub fn since_start(&self, at: Instant) -> Duration {
+        at.saturating_duration_since(self.start)
+    }
+
+    pub fn keys(&self) -> Keys<'_, ThreadKey, SpiedRecordQueue> {
         self.map.keys()
     }
-    pub fn iter(&self) -> Iter<'_, Tid, SpiedRecordQueue> {
+    pub fn iter(&self) -> Iter<'_, ThreadKey, SpiedRecordQueue> {
         self.map.iter()
     }
-    pub fn get(&self, k: &Tid) -> Option<&SpiedRecordQueue> {
+    pub fn get(&self, k: &ThreadKey) -> Option<&SpiedRecordQueue> {
         self.map.get(k)
     }
     pub fn len(&self) -> usize {
         self.map.len()
     }
-    pub fn contains_key(&self, k: &Tid) -> bool {
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    pub fn contains_key(&self, k: &ThreadKey) -> bool {
         self.map.contains_key(k)
     }
 
+    /// Remembers that `pid` is gone; its queues stay until the rules forget them.
+    pub fn mark_exited(&mut self, pid: Pid) {
+        self.exited.insert(pid);
+    }
+
+    pub fn is_exited(&self, pid: Pid) -> bool {
+        self.exited.contains(&pid)
+    }
+
     pub fn with_rules(&mut self, rules: Vec<ForgetRules>) {
         self.rules = rules;
     }
 
-    pub fn increment(&mut self, trace: &StackTrace) {
-        let now = Instant::now();
+    pub fn with_compaction(&mut self, rules: Vec<CompactionRule>) {
+        self.compaction = rules
+            .into_iter()
+            .map(|rule| (rule, Duration::ZERO))
+            .collect();
+    }
+
+    /// Takes the time from `clock` from now on, starting the session over at its now.
+    pub fn with_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.start = clock.now();
+        for (_, done) in self.compaction.iter_mut() {
+            *done = Duration::ZERO;
+        }
+        self.clock = clock;
+    }
 
+    /// Drops whatever the rules say should be forgotten by `at`.
+    pub fn forget(&mut self, at: Instant) {
         self.map.retain(|_, queue| {
             while let Some(top) = queue.finished_events.peek() {
                 match top.forget_time {
                     ForgetTime::Never => return true,
                     ForgetTime::When(time) => {
-                        if time > now {
+                        if time > at {
                             return true;
                         } else {
-                            queue.finished_events.pop().unwrap();
+                            queue.pop_finished();
                         }
                     }
                 }
             }
             !queue.unfinished_events.is_empty()
-                && match forget_time(&self.rules, queue.start_ts, queue.last_update) {
-                    ForgetTime::When(when) => when > now,
+                && match forget_time(&self.rules, None, queue.start_ts, queue.last_update) {
+                    ForgetTime::When(when) => when > at,
                     ForgetTime::Never => true,
                 }
         });
+        self.enforce_budgets();
+        for queue in self.map.values_mut() {
+            queue.prune_gaps();
+        }
+    }
+
+    /// Merges old short records as the compaction rules say, one whole bucket at a time.
+    pub fn compact(&mut self, at: Instant) {
+        for (rule, done) in self.compaction.iter_mut() {
+            if rule.bucket.is_zero() {
+                continue;
+            }
+            let cutoff = at
+                .saturating_duration_since(self.start)
+                .saturating_sub(rule.older_than);
+            let until = rule.bucket * (cutoff.div_duration_f64(rule.bucket) as u32);
+            if until <= *done {
+                continue;
+            }
+            let start = self.start;
+            let bucket = rule.bucket.as_nanos();
+            for queue in self.map.values_mut() {
+                queue.compact(
+                    start + *done..start + until,
+                    rule.shorter_than,
+                    |at| at.saturating_duration_since(start).as_nanos() / bucket,
+                    &self.rules,
+                );
+            }
+            *done = until;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        for (_, done) in self.compaction.iter_mut() {
+            *done = Duration::ZERO;
+        }
+        self.exited.clear();
+        self.last_sampled.clear();
+        self.gap_count = 0;
+        self.evicted = 0;
+    }
+
+    pub fn health(&self) -> &SamplerHealth {
+        &self.health
+    }
+
+    /// How many records are kept across all queues, and roughly how many bytes they take.
+    pub fn record_footprint(&self) -> (usize, usize) {
+        self.map
+            .values()
+            .map(|queue| {
+                queue
+                    .unfinished_events
+                    .iter()
+                    .map(|record| {
+                        size_of::<UnfinishedRecord>()
+                            + key_bytes(&record.frame_key)
+                            + record
+                                .locals
+                                .iter()
+                                .flatten()
+                                .map(|local| {
+                                    size_of::<LocalVariable>()
+                                        + local.name.capacity()
+                                        + local.repr.as_ref().map_or(0, String::capacity)
+                                })
+                                .sum::<usize>()
+                    })
+                    .fold(
+                        (queue.finished_len(), queue.finished_bytes()),
+                        |(count, bytes), size| (count + 1, bytes + size),
+                    )
+            })
+            .fold((0, 0), |(count, bytes), (queue_count, queue_bytes)| {
+                (count + queue_count, bytes + queue_bytes)
+            })
+    }
+
+    /// Pins the finished record of thread `key` at `depth` that started at `start`.
+    pub fn pin(&mut self, key: &ThreadKey, depth: usize, start: Instant) -> bool {
+        self.map
+            .get_mut(key)
+            .is_some_and(|queue| queue.pin(depth, start))
+    }
+
+    /// How many records the budget rules made forget before their time.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Forgets finished records early wherever a budget rule is exceeded.
+    fn enforce_budgets(&mut self) {
+        for rule in self.rules.iter() {
+            let ForgetRules::Budget {
+                per_thread,
+                records,
+                bytes,
+            } = *rule
+            else {
+                continue;
+            };
+            let over = |count: usize, size: usize| {
+                records.is_some_and(|max| count > max) || bytes.is_some_and(|max| size > max)
+            };
+            if per_thread {
+                for queue in self.map.values_mut() {
+                    while over(queue.finished_len(), queue.finished_bytes())
+                        && queue.finished_events.peek().is_some_and(|r| !r.pinned)
+                        && queue.pop_finished().is_some()
+                    {
+                        self.evicted += 1;
+                    }
+                }
+            } else {
+                let (mut count, mut size) = self.map.values().fold((0, 0), |(count, size), q| {
+                    (count + q.finished_len(), size + q.finished_bytes())
+                });
+                while over(count, size) {
+                    // whichever thread holds the record that is due to be forgotten first
+                    let Some(record) = self
+                        .map
+                        .values_mut()
+                        .filter_map(|q| {
+                            let top = q.finished_events.peek().filter(|r| !r.pinned)?;
+                            Some(((top.forget_time, top.end, top.id), q))
+                        })
+                        .min_by_key(|(due, _)| *due)
+                        .and_then(|(_, q)| q.pop_finished())
+                    else {
+                        break;
+                    };
+                    count -= 1;
+                    size -= record_bytes(&record);
+                    self.evicted += 1;
+                }
+            }
+        }
+    }
+
+    /// How many times a sampler stalled for long enough to leave a gap.
+    pub fn gap_count(&self) -> usize {
+        self.gap_count
+    }
 
-        let mut queue = self
-            .map
-            .remove(&(trace.thread_id as Tid))
-            .unwrap_or_else(|| {
-                SpiedRecordQueue::new(ThreadInfo {
+    /// Adds every trace of one sample that a sampler attached to `pid` took at `at`,
+    /// noting how well the sampler keeps up and a gap in the data if its previous
+    /// sample is too long ago.
+    pub fn increment_sample(
+        &mut self,
+        pid: Pid,
+        sample: &Sample,
+        at: Instant,
+        sampling_period: Duration,
+    ) {
+        self.health.note_sample(
+            pid,
+            at,
+            sample.late.is_some(),
+            sample.sampling_errors.as_ref().map_or(0, Vec::len),
+        );
+        let traces = &sample.traces;
+        if let Some(last) = self.last_sampled.insert(pid, at)
+            && at.saturating_duration_since(last) > sampling_period * GAP_PERIODS
+        {
+            self.gap_count += 1;
+            // subprocesses are sampled along with `pid`
+            for queue in self.map.values_mut().filter(|queue| {
+                queue.thread_info.pid == pid
+                    || traces
+                        .iter()
+                        .any(|trace| trace.pid == queue.thread_info.pid)
+            }) {
+                queue.gaps.push(last..at);
+            }
+        }
+        for trace in traces {
+            self.increment_at(trace, at);
+        }
+    }
+
+    pub fn increment(&mut self, trace: &StackTrace) {
+        self.increment_at(trace, self.clock.now());
+    }
+
+    /// Adds a stack trace sampled at `at`.
+    ///
+    /// A frame that changed between two samples did so somewhere in between them,
+    /// so frames end, and their successors start, at the midpoint of the two.
+    /// Across a gap there is no telling what ran, so every frame ends where the gap
+    /// starts and whatever runs after it starts afresh where it ends.
+    pub fn increment_at(&mut self, trace: &StackTrace, at: Instant) {
+        self.forget(at);
+        self.compact(at);
+
+        let key = (trace.pid, trace.thread_id as Tid);
+        let mut queue = self.map.remove(&key).unwrap_or_else(|| {
+            SpiedRecordQueue::new(
+                ThreadInfo {
                     name: trace.thread_name.clone(),
                     pid: trace.pid,
                     tid: trace.thread_id as Tid,
-                })
-            });
+                },
+                at,
+            )
+        });
+        // samples of one thread arrive in order, but stay safe from clocks read on other threads
+        let at = at.max(queue.last_update);
+        let gap = queue.gap_since_update().cloned();
+        let (ended_at, started_at) = match &gap {
+            Some(gap) => (gap.start.clamp(queue.last_update, at), at),
+            None => {
+                let changed_at = queue.last_update + (at - queue.last_update) / 2;
+                (changed_at, changed_at)
+            }
+        };
 
-        let mut prev_frames = queue.unfinished_events;
+        let mut prev_frames = std::mem::take(&mut queue.unfinished_events);
 
         let mut new_idx = 0;
 
         for (prev, new) in prev_frames.iter_mut().zip(trace.frames.iter().rev()) {
-            if prev.frame_key.should_merge(new) {
+            if gap.is_none() && prev.frame_key.should_merge(new) {
                 prev.locals = new.locals.clone();
+                prev.counts.add(trace);
                 new_idx += 1;
                 continue;
             } else {
@@ -247,13 +1108,27 @@ impl SpiedRecordQueueMap {
 
         for depth in (new_idx..prev_frames.len()).rev() {
             let unfinished = prev_frames.pop().unwrap(); // safe
-            queue.finished_events.push(event(
-                unfinished.frame_key,
-                unfinished.start,
-                now,
-                depth,
-                forget_time(&self.rules, unfinished.start, now),
-            ));
+            let forget_time = if unfinished.pinned {
+                ForgetTime::Never
+            } else {
+                forget_time(
+                    &self.rules,
+                    Some((&unfinished.frame_key, depth)),
+                    unfinished.start,
+                    ended_at,
+                )
+            };
+            queue.finish(FinishedRecord {
+                pinned: unfinished.pinned,
+                ..event(
+                    unfinished.frame_key,
+                    unfinished.start,
+                    ended_at,
+                    depth,
+                    unfinished.counts,
+                    forget_time,
+                )
+            });
         }
 
         for frame in trace.frames[..trace.frames.len().saturating_sub(new_idx)]
@@ -261,26 +1136,30 @@ impl SpiedRecordQueueMap {
             .rev()
         {
             prev_frames.push(UnfinishedRecord {
-                start: now,
-                frame_key: FrameKey {
-                    filename: frame.filename.clone(),
-                    name: frame.name.clone(),
-                },
+                start: started_at,
+                counts: SampleCounts::of(trace),
+                pinned: false,
+                frame_key: FrameKey::from(frame),
                 locals: frame.locals.clone(),
             });
         }
 
         // Save this stack trace for the next iteration.
         queue.unfinished_events = prev_frames;
-        queue.last_update = now;
+        queue.last_update = at;
+        if trace.active {
+            queue.last_active = Some(at);
+        }
 
-        self.map.insert(trace.thread_id as Tid, queue);
+        self.map.insert(key, queue);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::{sample, stack};
     use py_spy::stack_trace::StackTrace;
 
     #[test]
@@ -290,11 +1169,17 @@ mod tests {
             frame_key: FrameKey {
                 filename: "".to_string(),
                 name: "".to_string(),
+                module: None,
+                python_module: None,
             },
             start: now,
             end: now,
             depth: 0,
+            counts: SampleCounts::default(),
+            aggregate: None,
+            pinned: false,
             forget_time: ForgetTime::When(now),
+            id: 0,
         };
 
         let rec2 = FinishedRecord {
@@ -341,12 +1226,12 @@ mod tests {
         };
 
         queues.increment(&trace);
-        assert_eq!(queues.map[&1].unfinished_events.len(), 2);
-        assert_eq!(queues.map[&1].finished_events.len(), 0);
+        assert_eq!(queues.map[&(1, 1)].unfinished_events.len(), 2);
+        assert_eq!(queues.map[&(1, 1)].finished_len(), 0);
 
         queues.increment(&trace);
-        assert_eq!(queues.map[&1].unfinished_events.len(), 2);
-        assert_eq!(queues.map[&1].finished_events.len(), 0);
+        assert_eq!(queues.map[&(1, 1)].unfinished_events.len(), 2);
+        assert_eq!(queues.map[&(1, 1)].finished_len(), 0);
 
         queues.increment(&StackTrace {
             frames: vec![
@@ -367,7 +1252,7 @@ mod tests {
             ..trace.clone()
         });
         assert_eq!(
-            queues.map[&1]
+            queues.map[&(1, 1)]
                 .unfinished_events
                 .iter()
                 .map(|event| event.frame_key.name.clone())
@@ -375,9 +1260,8 @@ mod tests {
             vec!["level0", "level1_different", "level2", "level3"]
         );
         assert_eq!(
-            queues.map[&1]
-                .finished_events
-                .iter()
+            queues.map[&(1, 1)]
+                .finished()
                 .map(|event| event.frame_key.name.clone())
                 .collect::<Vec<String>>(),
             vec!["level1",]
@@ -398,7 +1282,7 @@ mod tests {
             ..trace.clone()
         });
         assert_eq!(
-            queues.map[&1]
+            queues.map[&(1, 1)]
                 .unfinished_events
                 .iter()
                 .map(|event| event.frame_key.name.clone())
@@ -406,12 +1290,11 @@ mod tests {
             vec!["level0", "level1_different", "level2_different"]
         );
         assert_eq!(
-            queues.map[&1]
-                .finished_events
-                .iter()
+            queues.map[&(1, 1)]
+                .finished()
                 .map(|event| event.frame_key.name.clone())
                 .collect::<Vec<String>>(),
-            vec!["level1", "level3", "level2"]
+            vec!["level1", "level2", "level3"]
         );
 
         queues.increment(&StackTrace {
@@ -423,8 +1306,476 @@ mod tests {
             ..trace.clone()
         });
 
-        assert_eq!(queues.map[&1].finished_events.len(), 3);
-        assert_eq!(queues.map[&1].unfinished_events.len(), 3);
-        assert_eq!(queues.map[&2].unfinished_events.len(), 1);
+        assert_eq!(queues.map[&(1, 1)].finished_len(), 3);
+        assert_eq!(queues.map[&(1, 1)].unfinished_events.len(), 3);
+        assert_eq!(queues.map[&(1, 2)].unfinished_events.len(), 1);
+    }
+
+    #[test]
+    fn test_restarted_process_keeps_history() {
+        let mut queues = SpiedRecordQueueMap::default();
+
+        queues.increment(&stack(1, 1, &["main"], true, false));
+        queues.mark_exited(1);
+        // the new process reuses the thread id of the old one
+        queues.increment(&stack(2, 1, &["main"], true, false));
+
+        assert_eq!(queues.len(), 2);
+        assert!(queues.is_exited(1));
+        assert!(!queues.is_exited(2));
+    }
+
+    #[test]
+    fn test_frames_change_halfway_between_samples() {
+        let mut queues = SpiedRecordQueueMap::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        queues.increment_at(&stack(1, 1, &["main", "work"], true, false), at(0));
+        queues.increment_at(&stack(1, 1, &["main", "work"], true, false), at(100));
+        queues.increment_at(&stack(1, 1, &["main", "rest"], true, false), at(200));
+
+        let queue = &queues.map[&(1, 1)];
+        let work = queue.finished().next().unwrap();
+        assert_eq!((work.start, work.end), (at(0), at(150)));
+        assert_eq!(queue.unfinished_events[0].start, at(0));
+        assert_eq!(queue.unfinished_events[1].start, at(150));
+        assert_eq!(queue.last_update, at(200));
+    }
+
+    #[test]
+    fn test_frames_end_where_a_gap_starts() {
+        let mut queues = SpiedRecordQueueMap::default();
+        queues.with_rules(vec![ForgetRules::RectLinear {
+            at_least: Duration::from_secs(2),
+            ratio: 0.0,
+        }]);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let period = Duration::from_millis(100);
+
+        for (millis, name) in [(0, "work"), (100, "work"), (1000, "rest"), (1100, "rest")] {
+            queues.increment_sample(
+                1,
+                &sample(vec![stack(1, 1, &["main", name], true, false)]),
+                at(millis),
+                period,
+            );
+        }
+
+        let queue = &queues.map[&(1, 1)];
+        assert_eq!(queue.gaps, vec![at(100)..at(1000)]);
+        assert_eq!(
+            queue
+                .finished()
+                .map(|record| (record.frame_key.name.as_str(), record.start, record.end))
+                .collect::<Vec<_>>(),
+            vec![("main", at(0), at(100)), ("work", at(0), at(100))]
+        );
+        assert_eq!(queue.unfinished_events[0].start, at(1000));
+        assert_eq!(queue.unfinished_events[1].start, at(1000));
+
+        // once what ran before it is forgotten, the gap goes too
+        queues.forget(at(2200));
+        let queue = &queues.map[&(1, 1)];
+        assert_eq!(queue.finished_len(), 0);
+        assert!(queue.gaps.is_empty());
+    }
+
+    #[test]
+    fn test_finished_in_window() {
+        let mut queues = SpiedRecordQueueMap::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // `main` stays on the stack while `step` is called ten times, 100ms apart
+        for i in 0..20 {
+            let inner = if i % 2 == 0 { "step" } else { "log" };
+            queues.increment_at(&stack(1, 1, &["main", inner], true, false), at(i * 50));
+        }
+        queues.increment_at(&stack(1, 1, &["main"], true, false), at(1000));
+
+        let queue = &queues.map[&(1, 1)];
+        assert_eq!(queue.finished_len(), 20);
+        assert_eq!(queue.max_finished_depth(), 1);
+        let visible = |depths, from, to| {
+            queue
+                .finished_in(depths, at(from), at(to))
+                .map(|record| (record.frame_key.name.as_str(), record.start))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            visible(0..2, 310, 420),
+            vec![("step", at(375)), ("log", at(325)), ("step", at(275))]
+        );
+        assert_eq!(visible(0..1, 0, 1000), vec![]);
+        assert_eq!(visible(1..5, 970, 2000), vec![("log", at(925))]);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let mut queues = SpiedRecordQueueMap::default();
+        let start = queues.start;
+        queues.with_compaction(vec![CompactionRule {
+            older_than: Duration::from_secs(1),
+            shorter_than: Duration::from_millis(200),
+            bucket: Duration::from_millis(500),
+        }]);
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // 150ms of `step` then 50ms of `log`, ten times over
+        for i in 0..40 {
+            let inner = if i % 4 == 3 { "log" } else { "step" };
+            queues.increment_at(&stack(1, 1, &["main", inner], true, false), at(i * 50));
+        }
+        for i in 40..=60 {
+            queues.increment_at(&stack(1, 1, &["main"], true, false), at(i * 50));
+        }
+
+        let queue = &queues.map[&(1, 1)];
+        let records = queue.finished().collect::<Vec<_>>();
+        assert!(records.len() < 10, "{} records left", records.len());
+        assert!(records.iter().any(|record| record.aggregate.is_some()));
+        // nothing got lost in merging
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.aggregate.map_or(1, |aggregate| aggregate.records))
+                .sum::<u64>(),
+            20
+        );
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.counts.samples)
+                .sum::<u64>(),
+            40
+        );
+        for record in records.iter().filter(|record| record.aggregate.is_some()) {
+            assert_eq!(record.frame_key.name, "step");
+        }
+        assert_eq!(
+            queue.finished_in(0..2, at(0), at(3000)).count(),
+            records.len()
+        );
+    }
+
+    #[test]
+    fn test_compaction_keeps_records_within_their_parent() {
+        let mut queues = SpiedRecordQueueMap::default();
+        let start = queues.start;
+        queues.with_compaction(vec![CompactionRule {
+            older_than: Duration::from_millis(100),
+            shorter_than: Duration::from_millis(30),
+            bucket: Duration::from_millis(500),
+        }]);
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // short calls under `a`, then short calls under `b`
+        for (millis, parent, inner) in [
+            (0, "a", "x"),
+            (10, "a", "z"),
+            (20, "a", "x"),
+            (30, "a", "z"),
+            (40, "b", "y"),
+            (50, "b", "z"),
+            (60, "b", "y"),
+            (70, "b", "z"),
+        ] {
+            queues.increment_at(
+                &stack(1, 1, &["main", parent, inner], true, false),
+                at(millis),
+            );
+        }
+        for millis in (80..=1000).step_by(10) {
+            queues.increment_at(&stack(1, 1, &["main"], true, false), at(millis));
+        }
+
+        let queue = &queues.map[&(1, 1)];
+        let merged = queue.finished_in(2..3, at(0), at(1000)).collect::<Vec<_>>();
+        assert_eq!(merged.len(), 2, "one merged record under each parent");
+        for record in merged {
+            assert!(record.aggregate.is_some());
+            let parent = queue
+                .finished_before(1, record.start + Duration::from_nanos(1))
+                .unwrap();
+            assert!(
+                parent.start <= record.start && record.end <= parent.end,
+                "{} at {:?} is outside {} at {:?}",
+                record.frame_key.name,
+                record.start - start..record.end - start,
+                parent.frame_key.name,
+                parent.start - start..parent.end - start,
+            );
+        }
+    }
+
+    #[test]
+    fn test_budget_evicts_first_to_be_forgotten() {
+        let mut queues = SpiedRecordQueueMap::default();
+        queues.with_rules(vec![
+            ForgetRules::RectLinear {
+                at_least: Duration::from_secs(3600),
+                ratio: 0.0,
+            },
+            ForgetRules::Budget {
+                per_thread: true,
+                records: Some(3),
+                bytes: None,
+            },
+            ForgetRules::Budget {
+                per_thread: false,
+                records: Some(4),
+                bytes: None,
+            },
+        ]);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // each thread finishes 9 records, thread 1 well before thread 2
+        for (thread_id, from) in [(1, 0), (2, 1000)] {
+            for i in 0..10 {
+                let name = if i % 2 == 0 { "a" } else { "b" };
+                queues.increment_at(
+                    &stack(1, thread_id, &[name], true, false),
+                    at(from + i * 100),
+                );
+            }
+        }
+        queues.forget(at(2000));
+
+        assert_eq!(queues.map[&(1, 2)].finished_len(), 3);
+        assert_eq!(queues.map[&(1, 1)].finished_len(), 1);
+        // thread 1 kept the record it finished last
+        assert_eq!(queues.map[&(1, 1)].finished().next().unwrap().end, at(850));
+        assert_eq!(queues.evicted(), 14);
+        assert_eq!(queues.record_footprint().0, 6);
+    }
+
+    #[test]
+    fn test_budget_evicts_oldest_when_kept_forever() {
+        let mut queues = SpiedRecordQueueMap::default();
+        // without rules that forget, every record is due never, all the same
+        queues.with_rules(vec![
+            ForgetRules::Budget {
+                per_thread: true,
+                records: Some(3),
+                bytes: None,
+            },
+            ForgetRules::Budget {
+                per_thread: false,
+                records: Some(4),
+                bytes: None,
+            },
+        ]);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        for (thread_id, from) in [(1, 0), (2, 1000)] {
+            for i in 0..10 {
+                let name = if i % 2 == 0 { "a" } else { "b" };
+                queues.increment_at(
+                    &stack(1, thread_id, &[name], true, false),
+                    at(from + i * 100),
+                );
+            }
+        }
+        queues.forget(at(2000));
+
+        let ends = |thread_id| {
+            let mut ends = queues.map[&(1, thread_id)]
+                .finished()
+                .map(|record| record.end)
+                .collect::<Vec<_>>();
+            ends.sort();
+            ends
+        };
+        assert_eq!(ends(1), vec![at(850)]);
+        assert_eq!(ends(2), vec![at(1650), at(1750), at(1850)]);
+    }
+
+    #[test]
+    fn test_matching_rules() {
+        #[derive(Deserialize)]
+        struct Rules {
+            rules: Vec<ForgetRules>,
+        }
+        let rules = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                [[rules]]
+                type = "matching"
+                function = "^(train_epoch|validate)$"
+                [[rules]]
+                type = "matching"
+                filename = "*/site-packages/torch/*"
+                after = "5s"
+                [[rules]]
+                type = "matching"
+                module = "data"
+                min_depth = 2
+                after = "1m"
+                [[rules]]
+                type = "rectlinear"
+                at_least = "30s"
+                ratio = 0.0
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize::<Rules>()
+            .unwrap()
+            .rules;
+        let key = |name: &str, filename: &str, short_filename: Option<&str>| {
+            FrameKey::from(&Frame {
+                name: name.to_string(),
+                filename: filename.to_string(),
+                module: None,
+                short_filename: short_filename.map(str::to_string),
+                line: 1,
+                locals: None,
+                is_entry: false,
+            })
+        };
+        let start = Instant::now();
+        let end = start + Duration::from_secs(1);
+        let forget = |key: &FrameKey, depth| forget_time(&rules, Some((key, depth)), start, end);
+        let after = |secs| ForgetTime::When(end + Duration::from_secs(secs));
+
+        assert_eq!(
+            forget(&key("train_epoch", "train.py", None), 3),
+            ForgetTime::Never
+        );
+        let torch = key(
+            "forward",
+            "/venv/lib/site-packages/torch/nn/linear.py",
+            None,
+        );
+        assert_eq!(forget(&torch, 5), after(5));
+        let loader = key(
+            "load",
+            "/home/me/project/data/loader.py",
+            Some("data/loader.py"),
+        );
+        assert_eq!(forget(&loader, 2), after(60));
+        assert_eq!(forget(&loader, 1), after(30));
+        let package = key(
+            "load",
+            "/venv/lib/python3.12/site-packages/data/__init__.py",
+            None,
+        );
+        assert_eq!(forget(&package, 2), after(60));
+        let metadata = key("load", "/home/me/project/metadata.py", Some("metadata.py"));
+        assert_eq!(forget(&metadata, 2), after(30));
+        assert_eq!(forget(&key("train", "train.py", None), 0), after(30));
+        // the rules for whole threads ignore matching rules
+        assert_eq!(forget_time(&rules, None, start, end), after(30));
+    }
+
+    #[test]
+    fn test_pinned_events_and_ancestors_are_kept() {
+        let mut queues = SpiedRecordQueueMap::default();
+        queues.with_rules(vec![
+            ForgetRules::RectLinear {
+                at_least: Duration::from_secs(1),
+                ratio: 0.0,
+            },
+            ForgetRules::Budget {
+                per_thread: true,
+                records: Some(1),
+                bytes: None,
+            },
+        ]);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        queues.increment_at(&stack(1, 1, &["main", "epoch", "step"], true, false), at(0));
+        queues.increment_at(
+            &stack(1, 1, &["main", "epoch", "log"], true, false),
+            at(100),
+        );
+        let step = queues.map[&(1, 1)].finished_at(2, at(0)).unwrap();
+        assert_eq!(step.end, at(50));
+        assert!(queues.pin(&(1, 1), 2, at(0)));
+        assert!(!queues.pin(&(1, 1), 2, at(0)), "pinned already");
+
+        // `epoch` was still running when `step` got pinned
+        queues.increment_at(&stack(1, 1, &["main", "eval"], true, false), at(200));
+        for millis in (300..5000).step_by(100) {
+            let inner = if millis % 200 == 0 { "a" } else { "b" };
+            queues.increment_at(
+                &stack(1, 1, &["main", "other", inner], true, false),
+                at(millis),
+            );
+        }
+        queues.forget(at(5000));
+
+        let queue = &queues.map[&(1, 1)];
+        assert_eq!(queue.pinned().len(), 1);
+        assert_eq!(queue.pinned()[0].frame_key.name, "step");
+        assert!(queue.finished_at(2, at(0)).unwrap().pinned);
+        assert!(queue.finished_at(1, at(0)).unwrap().pinned);
+        // all the rest fell to the budget
+        assert_eq!(queue.finished_len(), 2);
+    }
+
+    #[test]
+    fn test_counts_activity() {
+        let mut queues = SpiedRecordQueueMap::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        queues.increment_at(&stack(1, 1, &["main", "work"], true, true), at(0));
+        queues.increment_at(&stack(1, 1, &["main", "work"], true, true), at(100));
+        queues.increment_at(&stack(1, 1, &["main", "work"], true, false), at(200));
+        queues.increment_at(&stack(1, 1, &["main", "wait"], false, false), at(300));
+        assert!(!queues.map[&(1, 1)].is_idle());
+        queues.increment_at(&stack(1, 1, &["main", "wait"], false, false), at(1400));
+
+        let queue = &queues.map[&(1, 1)];
+        let work = queue.finished().next().unwrap();
+        assert_eq!(
+            work.counts,
+            SampleCounts {
+                samples: 3,
+                active: 3,
+                owns_gil: 2
+            }
+        );
+        assert_eq!(work.counts.activity(), Activity::Busy);
+        // held the GIL in 2 of its 5 samples
+        assert_eq!(
+            queue.unfinished_events[0].counts.activity(),
+            Activity::WithoutGil
+        );
+        assert_eq!(queue.unfinished_events[1].counts.activity(), Activity::Idle);
+        assert!(queue.is_idle());
+    }
+
+    #[test]
+    fn test_forget_with_clock() {
+        let clock = ManualClock::default();
+        let mut queues = SpiedRecordQueueMap::default();
+        queues.with_clock(Arc::new(clock.clone()));
+        queues.with_rules(vec![ForgetRules::RectLinear {
+            at_least: Duration::from_secs(10),
+            ratio: 1.0,
+        }]);
+
+        queues.increment(&stack(1, 1, &["short"], true, false));
+        clock.advance(Duration::from_secs(2));
+        // `short` lasted 1s, so it is forgotten 10s + 1s after it ended at 1s
+        queues.increment(&stack(1, 1, &["long"], true, false));
+        assert_eq!(queues.map[&(1, 1)].finished_len(), 1);
+
+        clock.set(queues.start + Duration::from_millis(11_900));
+        queues.forget(clock.now());
+        assert_eq!(queues.map[&(1, 1)].finished_len(), 1);
+
+        clock.advance(Duration::from_millis(200));
+        queues.forget(clock.now());
+        assert_eq!(queues.map[&(1, 1)].finished_len(), 0);
+        assert_eq!(queues.map[&(1, 1)].unfinished_events.len(), 1);
     }
 }
@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Error;
+use py_spy::stack_trace::{Frame, LocalVariable, StackTrace};
+use remoteprocess::Pid;
+use serde::{Deserialize, Serialize};
+
+const RECORD_VERSION: u32 = 2;
+// the sampler thread never returns, so nothing would flush on drop
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RecordedLocal {
+    pub name: String,
+    pub addr: usize,
+    pub arg: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repr: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub name: String,
+    pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub short_filename: Option<String>,
+    pub line: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locals: Option<Vec<RecordedLocal>>,
+    #[serde(default)]
+    pub is_entry: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedTrace {
+    pub pid: Pid,
+    pub thread_id: u64,
+    pub thread_name: Option<String>,
+    pub os_thread_id: Option<u64>,
+    pub active: bool,
+    pub owns_gil: bool,
+    pub frames: Vec<RecordedFrame>,
+}
+
+// A trace as written, its frames being positions in the frame table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredTrace {
+    pid: Pid,
+    thread_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    os_thread_id: Option<u64>,
+    active: bool,
+    owns_gil: bool,
+    frames: Vec<usize>,
+    // the locals of the frames that have any, by their index in `frames`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    locals: Vec<(usize, Vec<RecordedLocal>)>,
+}
+
+// One json object per line, the first line being the header. Every frame is written
+// once, without its locals, before the first sample that refers to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum RecordLine {
+    Header {
+        version: u32,
+        // wall clock time at which recording started
+        unix_micros: u64,
+    },
+    Frame(RecordedFrame),
+    Sample {
+        // time since recording started
+        micros: u64,
+        traces: Vec<StoredTrace>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedSample {
+    pub elapsed: Duration,
+    pub traces: Vec<RecordedTrace>,
+}
+
+impl From<&LocalVariable> for RecordedLocal {
+    fn from(local: &LocalVariable) -> Self {
+        Self {
+            name: local.name.clone(),
+            addr: local.addr,
+            arg: local.arg,
+            repr: local.repr.clone(),
+        }
+    }
+}
+
+impl From<RecordedLocal> for LocalVariable {
+    fn from(local: RecordedLocal) -> Self {
+        Self {
+            name: local.name,
+            addr: local.addr,
+            arg: local.arg,
+            repr: local.repr,
+        }
+    }
+}
+
+impl From<&Frame> for RecordedFrame {
+    fn from(frame: &Frame) -> Self {
+        Self {
+            name: frame.name.clone(),
+            filename: frame.filename.clone(),
+            module: frame.module.clone(),
+            short_filename: frame.short_filename.clone(),
+            line: frame.line,
+            locals: frame
+                .locals
+                .as_ref()
+                .map(|locals| locals.iter().map(RecordedLocal::from).collect()),
+            is_entry: frame.is_entry,
+        }
+    }
+}
+
+impl From<RecordedFrame> for Frame {
+    fn from(frame: RecordedFrame) -> Self {
+        Self {
+            name: frame.name,
+            filename: frame.filename,
+            module: frame.module,
+            short_filename: frame.short_filename,
+            line: frame.line,
+            locals: frame
+                .locals
+                .map(|locals| locals.into_iter().map(LocalVariable::from).collect()),
+            is_entry: frame.is_entry,
+        }
+    }
+}
+
+impl From<&StackTrace> for RecordedTrace {
+    fn from(trace: &StackTrace) -> Self {
+        Self {
+            pid: trace.pid,
+            thread_id: trace.thread_id,
+            thread_name: trace.thread_name.clone(),
+            os_thread_id: trace.os_thread_id,
+            active: trace.active,
+            owns_gil: trace.owns_gil,
+            frames: trace.frames.iter().map(RecordedFrame::from).collect(),
+        }
+    }
+}
+
+impl From<RecordedTrace> for StackTrace {
+    fn from(trace: RecordedTrace) -> Self {
+        Self {
+            pid: trace.pid,
+            thread_id: trace.thread_id,
+            thread_name: trace.thread_name,
+            os_thread_id: trace.os_thread_id,
+            active: trace.active,
+            owns_gil: trace.owns_gil,
+            frames: trace.frames.into_iter().map(Frame::from).collect(),
+            process_info: None,
+        }
+    }
+}
+
+pub struct SampleRecorder<W: Write> {
+    writer: W,
+    start: Instant,
+    // where each frame written so far is in the frame table
+    frames: HashMap<RecordedFrame, usize>,
+    flushed: Instant,
+}
+
+impl SampleRecorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> SampleRecorder<W> {
+    pub fn new(mut writer: W) -> Result<Self, Error> {
+        let header = RecordLine::Header {
+            version: RECORD_VERSION,
+            unix_micros: SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64,
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+        let start = Instant::now();
+        Ok(Self {
+            writer,
+            start,
+            frames: HashMap::new(),
+            flushed: start,
+        })
+    }
+
+    fn write_line(&mut self, line: &RecordLine) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.writer, line)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    // Writes the frames of `trace` not seen before, then refers to them by position.
+    fn store(&mut self, trace: &StackTrace) -> Result<StoredTrace, Error> {
+        let mut frames = Vec::with_capacity(trace.frames.len());
+        let mut locals = Vec::new();
+        for (idx, frame) in trace.frames.iter().enumerate() {
+            let mut frame = RecordedFrame::from(frame);
+            if let Some(frame_locals) = frame.locals.take() {
+                locals.push((idx, frame_locals));
+            }
+            let position = match self.frames.get(&frame) {
+                Some(position) => *position,
+                None => {
+                    let position = self.frames.len();
+                    self.write_line(&RecordLine::Frame(frame.clone()))?;
+                    self.frames.insert(frame, position);
+                    position
+                }
+            };
+            frames.push(position);
+        }
+        Ok(StoredTrace {
+            pid: trace.pid,
+            thread_id: trace.thread_id,
+            thread_name: trace.thread_name.clone(),
+            os_thread_id: trace.os_thread_id,
+            active: trace.active,
+            owns_gil: trace.owns_gil,
+            frames,
+            locals,
+        })
+    }
+
+    /// Writes the traces of a sample `taken` at that time, flushing once a second.
+    pub fn record(&mut self, taken: Instant, traces: &[StackTrace]) -> Result<(), Error> {
+        let traces = traces
+            .iter()
+            .map(|trace| self.store(trace))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.write_line(&RecordLine::Sample {
+            micros: taken.saturating_duration_since(self.start).as_micros() as u64,
+            traces,
+        })?;
+        if taken.saturating_duration_since(self.flushed) >= FLUSH_EVERY {
+            self.writer.flush()?;
+            self.flushed = taken;
+        }
+        Ok(())
+    }
+}
+
+pub struct SampleReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+    pub unix_start: Duration,
+    frames: Vec<RecordedFrame>,
+}
+
+impl SampleReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> SampleReader<R> {
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut lines = reader.lines();
+        let first = lines
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("empty recording"))??;
+        match serde_json::from_str(&first)? {
+            RecordLine::Header {
+                version,
+                unix_micros,
+            } => {
+                if version != RECORD_VERSION {
+                    anyhow::bail!("unsupported recording version {}", version);
+                }
+                Ok(Self {
+                    lines,
+                    unix_start: Duration::from_micros(unix_micros),
+                    frames: Vec::new(),
+                })
+            }
+            _ => anyhow::bail!("recording is missing its header"),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for SampleReader<R> {
+    type Item = Result<RecordedSample, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(match serde_json::from_str(&line) {
+                Ok(RecordLine::Frame(frame)) => {
+                    self.frames.push(frame);
+                    continue;
+                }
+                Ok(RecordLine::Sample { micros, traces }) => traces
+                    .into_iter()
+                    .map(|trace| self.load(trace))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|traces| RecordedSample {
+                        elapsed: Duration::from_micros(micros),
+                        traces,
+                    }),
+                Ok(RecordLine::Header { .. }) => Err(anyhow::anyhow!("unexpected header")),
+                Err(err) => Err(err.into()),
+            });
+        }
+    }
+}
+
+impl<R: BufRead> SampleReader<R> {
+    // Looks the frames of `trace` up in the frame table read so far.
+    fn load(&self, trace: StoredTrace) -> Result<RecordedTrace, Error> {
+        let mut frames = trace
+            .frames
+            .iter()
+            .map(|position| {
+                self.frames
+                    .get(*position)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("frame {} is not recorded", position))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (idx, locals) in trace.locals {
+            frames
+                .get_mut(idx)
+                .ok_or_else(|| anyhow::anyhow!("locals of missing frame {}", idx))?
+                .locals = Some(locals);
+        }
+        Ok(RecordedTrace {
+            pid: trace.pid,
+            thread_id: trace.thread_id,
+            thread_name: trace.thread_name,
+            os_thread_id: trace.os_thread_id,
+            active: trace.active,
+            owns_gil: trace.owns_gil,
+            frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let trace = StackTrace {
+            pid: 3,
+            thread_id: 7,
+            thread_name: Some("MainThread".to_string()),
+            os_thread_id: Some(11),
+            active: true,
+            owns_gil: true,
+            frames: vec![Frame {
+                name: "level0".to_string(),
+                filename: "test.py".to_string(),
+                module: None,
+                short_filename: Some("test.py".to_string()),
+                line: 1,
+                locals: Some(vec![LocalVariable {
+                    name: "x".to_string(),
+                    addr: 10,
+                    arg: true,
+                    repr: Some("1".to_string()),
+                }]),
+                is_entry: true,
+            }],
+            process_info: None,
+        };
+
+        let mut buf = Vec::new();
+        let mut recorder = SampleRecorder::new(&mut buf).unwrap();
+        let start = recorder.start;
+        recorder
+            .record(
+                start + Duration::from_millis(5),
+                std::slice::from_ref(&trace),
+            )
+            .unwrap();
+        recorder
+            .record(start + Duration::from_millis(15), &[])
+            .unwrap();
+        recorder
+            .record(
+                start + Duration::from_millis(25),
+                std::slice::from_ref(&trace),
+            )
+            .unwrap();
+
+        // the frame is written once, with the locals kept by the samples
+        let written = String::from_utf8(buf.clone()).unwrap();
+        assert_eq!(written.matches(r#""kind":"frame""#).count(), 1);
+        assert_eq!(written.matches(r#""repr":"1""#).count(), 2);
+
+        let samples = SampleReader::new(buf.as_slice())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[0].elapsed, Duration::from_millis(5));
+        assert_eq!(samples[1].elapsed, Duration::from_millis(15));
+        assert_eq!(samples[0].traces, vec![RecordedTrace::from(&trace)]);
+        assert!(samples[1].traces.is_empty());
+        assert_eq!(samples[2].traces, samples[0].traces);
+
+        let rebuilt = StackTrace::from(samples[0].traces[0].clone());
+        assert_eq!(rebuilt.frames, trace.frames);
+        assert_eq!(rebuilt.thread_name, trace.thread_name);
+    }
+
+    #[test]
+    fn test_flushes_once_a_second() {
+        let mut recorder = SampleRecorder::new(BufWriter::new(Vec::new())).unwrap();
+        let start = recorder.start;
+        recorder
+            .record(start + Duration::from_millis(500), &[])
+            .unwrap();
+        assert!(recorder.writer.get_ref().is_empty());
+        recorder
+            .record(start + Duration::from_millis(1000), &[])
+            .unwrap();
+        assert_eq!(
+            recorder
+                .writer
+                .get_ref()
+                .iter()
+                .filter(|b| **b == b'\n')
+                .count(),
+            3
+        );
+    }
+}
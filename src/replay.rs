@@ -0,0 +1,427 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use itertools::Itertools;
+use py_spy::StackTrace;
+use py_spy::sampler::Sample;
+use remoteprocess::Pid;
+
+use crate::app::SamplerOps;
+use crate::clock::Clock;
+use crate::control::{CONTROL_POLL, SamplerControl};
+use crate::errors::AppError;
+use crate::import::import_samples;
+use crate::priority::SpiedRecordQueueMap;
+use crate::record::{RecordedSample, SampleReader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaySpeed {
+    Times(u32),
+    Max,
+}
+
+impl ReplaySpeed {
+    const ALL: [ReplaySpeed; 4] = [
+        ReplaySpeed::Times(1),
+        ReplaySpeed::Times(2),
+        ReplaySpeed::Times(10),
+        ReplaySpeed::Max,
+    ];
+
+    fn position(&self) -> usize {
+        Self::ALL.iter().position(|s| s == self).unwrap_or(0)
+    }
+
+    pub fn faster(self) -> Self {
+        Self::ALL[(self.position() + 1).min(Self::ALL.len() - 1)]
+    }
+
+    pub fn slower(self) -> Self {
+        Self::ALL[self.position().saturating_sub(1)]
+    }
+}
+
+impl std::fmt::Display for ReplaySpeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Times(factor) => write!(f, "{}x", factor),
+            Self::Max => write!(f, "max"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayStatus {
+    pub paused: bool,
+    pub speed: ReplaySpeed,
+    pub position: Duration,
+    pub length: Duration,
+    seek: Option<Duration>,
+}
+
+/// Shared handle through which the UI steers a running [`ReplaySampler`].
+///
+/// It is also the clock of a replay: its now is as far into the recording as
+/// the replay has got.
+#[derive(Debug, Clone)]
+pub struct ReplayControl {
+    status: Arc<Mutex<ReplayStatus>>,
+    // the instant the start of the recording is replayed at
+    origin: Instant,
+}
+
+impl ReplayControl {
+    fn new(length: Duration) -> Self {
+        Self {
+            origin: Instant::now(),
+            status: Arc::new(Mutex::new(ReplayStatus {
+                paused: false,
+                speed: ReplaySpeed::Times(1),
+                position: Duration::ZERO,
+                length,
+                seek: None,
+            })),
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ReplayStatus)) {
+        if let Ok(mut status) = self.status.lock() {
+            f(&mut status);
+        }
+    }
+
+    pub fn status(&self) -> Option<ReplayStatus> {
+        self.status.lock().ok().map(|status| *status)
+    }
+
+    pub fn toggle_pause(&self) {
+        self.update(|s| s.paused ^= true);
+    }
+
+    pub fn set_speed(&self, speed: ReplaySpeed) {
+        self.update(|s| s.speed = speed);
+    }
+
+    pub fn faster(&self) {
+        self.update(|s| s.speed = s.speed.faster());
+    }
+
+    pub fn slower(&self) {
+        self.update(|s| s.speed = s.speed.slower());
+    }
+
+    pub fn seek(&self, to: Duration) {
+        self.update(|s| s.seek = Some(to.min(s.length)));
+    }
+
+    pub fn seek_forward(&self, by: Duration) {
+        self.update(|s| s.seek = Some((s.seek.unwrap_or(s.position) + by).min(s.length)));
+    }
+
+    pub fn seek_backward(&self, by: Duration) {
+        self.update(|s| s.seek = Some(s.seek.unwrap_or(s.position).saturating_sub(by)));
+    }
+}
+
+impl Clock for ReplayControl {
+    fn now(&self) -> Instant {
+        self.origin
+            + self
+                .status()
+                .map_or(Duration::ZERO, |status| status.position)
+    }
+}
+
+/// Feeds a recorded session back into the queues as if it were being sampled live.
+pub struct ReplaySampler {
+    samples: Vec<RecordedSample>,
+    control: ReplayControl,
+    // the process that was followed, and how often it was sampled
+    pid: Pid,
+    period: Duration,
+}
+
+impl ReplaySampler {
+    pub fn new(samples: Vec<RecordedSample>) -> Self {
+        let length = samples.last().map_or(Duration::ZERO, |s| s.elapsed);
+        // subprocesses only ever start after the process that spawned them
+        let pid = samples
+            .iter()
+            .flat_map(|sample| sample.traces.iter())
+            .map(|trace| trace.pid)
+            .min()
+            .unwrap_or_default();
+        // recordings do not say how often they were sampled, so go by the mean spacing
+        let times = samples.iter().map(|sample| sample.elapsed).dedup().count();
+        let period = length / times.saturating_sub(1).max(1) as u32;
+        Self {
+            samples,
+            control: ReplayControl::new(length),
+            pid,
+            period,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Self::new(
+            SampleReader::open(path)?.collect::<Result<Vec<_>, _>>()?,
+        ))
+    }
+
+    /// Replays a py-spy folded or speedscope file, laid out at `sampling_rate`.
+    pub fn import<P: AsRef<Path>>(path: P, sampling_rate: u64) -> Result<Self, Error> {
+        Ok(Self::new(import_samples(path, sampling_rate)?))
+    }
+
+    pub fn control(&self) -> ReplayControl {
+        self.control.clone()
+    }
+}
+
+impl SamplerOps for ReplaySampler {
+    fn push_to_queue(
+        self,
+        record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>,
+        _control: SamplerControl,
+    ) -> Result<(), Error> {
+        let origin = self.control.origin;
+        // through the same path as live samples, so that gaps and health show alike
+        let feed = |samples: &[RecordedSample]| -> Result<(), Error> {
+            let mut queue = record_queue_map
+                .write()
+                .map_err(|_| AppError::SamplerSenderError)?;
+            for recorded in samples {
+                let sample = Sample {
+                    traces: recorded
+                        .traces
+                        .iter()
+                        .cloned()
+                        .map(StackTrace::from)
+                        .collect(),
+                    sampling_errors: None,
+                    late: None,
+                };
+                queue.increment_sample(self.pid, &sample, origin + recorded.elapsed, self.period);
+            }
+            Ok(())
+        };
+
+        let mut cursor = 0;
+        // wall clock time and replay position that pacing is measured from
+        let mut anchor = (Instant::now(), Duration::ZERO);
+        let mut last_settings = (false, ReplaySpeed::Times(1));
+
+        loop {
+            let mut status = self
+                .control
+                .status
+                .lock()
+                .map_err(|_| AppError::SamplerSenderError)?;
+
+            if let Some(target) = status.seek.take() {
+                let rewound = target < status.position;
+                let from = if rewound { 0 } else { cursor };
+                cursor = self.samples.partition_point(|s| s.elapsed < target);
+                status.position = target;
+                anchor = (Instant::now(), target);
+                // the queues read the clock, which needs the status, so let go of it first
+                drop(status);
+
+                if rewound {
+                    // whatever was fed after the target would now be in the future
+                    record_queue_map
+                        .write()
+                        .map_err(|_| AppError::SamplerSenderError)?
+                        .clear();
+                }
+                // everything before the target happened, whether seeking skipped over it or not
+                feed(&self.samples[from..cursor])?;
+                continue;
+            }
+
+            if (status.paused, status.speed) != last_settings {
+                last_settings = (status.paused, status.speed);
+                anchor = (Instant::now(), status.position);
+            }
+
+            let Some(sample) = self.samples.get(cursor).filter(|_| !status.paused) else {
+                drop(status);
+                thread::sleep(CONTROL_POLL);
+                continue;
+            };
+
+            if let ReplaySpeed::Times(factor) = status.speed {
+                let factor = factor.max(1);
+                let played = anchor.1 + anchor.0.elapsed() * factor;
+                if played < sample.elapsed {
+                    // keep the clock, and with it the timeline, moving between samples
+                    status.position = status.position.max(played);
+                    drop(status);
+                    thread::sleep(((sample.elapsed - played) / factor).min(CONTROL_POLL));
+                    continue;
+                }
+            }
+
+            status.position = status.position.max(sample.elapsed);
+            drop(status);
+
+            feed(std::slice::from_ref(sample))?;
+            cursor += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::SamplerSettings;
+    use crate::record::{RecordedFrame, RecordedTrace};
+
+    fn sample(millis: u64, name: &str) -> RecordedSample {
+        RecordedSample {
+            elapsed: Duration::from_millis(millis),
+            traces: vec![RecordedTrace {
+                pid: 1,
+                thread_id: 1,
+                thread_name: None,
+                os_thread_id: None,
+                active: true,
+                owns_gil: true,
+                frames: vec![RecordedFrame {
+                    name: name.to_string(),
+                    filename: "test.py".to_string(),
+                    module: None,
+                    short_filename: None,
+                    line: 1,
+                    locals: None,
+                    is_entry: true,
+                }],
+            }],
+        }
+    }
+
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            thread::sleep(CONTROL_POLL);
+        }
+        panic!("replay did not get there in time");
+    }
+
+    #[test]
+    fn test_speed_cycle() {
+        assert_eq!(ReplaySpeed::Times(1).slower(), ReplaySpeed::Times(1));
+        assert_eq!(ReplaySpeed::Times(1).faster(), ReplaySpeed::Times(2));
+        assert_eq!(ReplaySpeed::Times(10).faster(), ReplaySpeed::Max);
+        assert_eq!(ReplaySpeed::Max.faster(), ReplaySpeed::Max);
+    }
+
+    #[test]
+    fn test_seek_is_clamped() {
+        let control = ReplayControl::new(Duration::from_secs(5));
+        control.seek_forward(Duration::from_secs(10));
+        assert_eq!(control.status().unwrap().seek, Some(Duration::from_secs(5)));
+        control.seek_backward(Duration::from_secs(7));
+        assert_eq!(control.status().unwrap().seek, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_replay_drives_the_clock() {
+        let replay = ReplaySampler::new(vec![
+            sample(0, "load"),
+            sample(100, "train"),
+            sample(200, "save"),
+        ]);
+        let control = replay.control();
+        control.set_speed(ReplaySpeed::Max);
+        let queues = Arc::new(RwLock::new(SpiedRecordQueueMap::default()));
+        queues
+            .write()
+            .unwrap()
+            .with_clock(Arc::new(control.clone()));
+        let origin = control.now();
+
+        let fed = Arc::clone(&queues);
+        let sampler_control = SamplerControl::new(SamplerSettings {
+            paused: false,
+            sampling_rate: 100,
+            native: false,
+            dump_locals: 0,
+        });
+        thread::spawn(move || replay.push_to_queue(fed, sampler_control));
+
+        let spans = || {
+            let queues = queues.read().unwrap();
+            queues.get(&(1, 1)).map_or(Vec::new(), |queue| {
+                queue
+                    .finished()
+                    .map(|record| (record.frame_key.name.clone(), record.end - origin))
+                    .collect::<Vec<_>>()
+            })
+        };
+        wait_until(|| spans().len() == 2);
+        // stamped where they are in the recording, however fast it is replayed
+        assert_eq!(
+            spans(),
+            vec![
+                ("load".to_string(), Duration::from_millis(50)),
+                ("train".to_string(), Duration::from_millis(150)),
+            ]
+        );
+        assert_eq!(control.now(), origin + Duration::from_millis(200));
+
+        control.toggle_pause();
+        control.seek(Duration::from_millis(150));
+        wait_until(|| spans().len() == 1);
+        assert_eq!(
+            spans(),
+            vec![("load".to_string(), Duration::from_millis(50))]
+        );
+        assert_eq!(control.now(), origin + Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_replay_shows_gaps() {
+        let samples = (0..100)
+            .step_by(10)
+            .chain((1000..1100).step_by(10))
+            .map(|millis| sample(millis, "train"))
+            .collect();
+        let replay = ReplaySampler::new(samples);
+        let control = replay.control();
+        control.set_speed(ReplaySpeed::Max);
+        let queues = Arc::new(RwLock::new(SpiedRecordQueueMap::default()));
+        queues
+            .write()
+            .unwrap()
+            .with_clock(Arc::new(control.clone()));
+        let origin = control.now();
+
+        let fed = Arc::clone(&queues);
+        let sampler_control = SamplerControl::new(SamplerSettings {
+            paused: false,
+            sampling_rate: 100,
+            native: false,
+            dump_locals: 0,
+        });
+        thread::spawn(move || replay.push_to_queue(fed, sampler_control));
+
+        wait_until(|| control.now() == origin + Duration::from_millis(1090));
+        let queues = queues.read().unwrap();
+        assert_eq!(queues.gap_count(), 1);
+        assert_eq!(
+            queues.get(&(1, 1)).unwrap().gaps,
+            vec![origin + Duration::from_millis(90)..origin + Duration::from_millis(1000)]
+        );
+        // the samples of the last second count towards the health of the replayed process
+        assert_eq!(
+            queues.health().rate(origin + Duration::from_millis(1090)),
+            (11.0, 1)
+        );
+    }
+}
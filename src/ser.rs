@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use py_spy::config::LockingStrategy;
+use regex::Regex;
 use serde::{Deserialize, Deserializer};
 
 pub fn parse_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
@@ -40,3 +41,67 @@ where
         ))),
     }
 }
+
+pub fn parse_size<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    let (value, multiplier) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        None => (&s[..], 1),
+        Some((split, _)) => (
+            &s[..split],
+            match &s[split..] {
+                "B" => 1,
+                "KiB" => 1 << 10,
+                "MiB" => 1 << 20,
+                "GiB" => 1 << 30,
+                unit => {
+                    return Err(serde::de::Error::custom(format!(
+                        "invalid size unit '{}'",
+                        unit
+                    )));
+                }
+            },
+        ),
+    };
+    let value: usize = value
+        .parse()
+        .map_err(|_| "invalid unsigned integer")
+        .map_err(serde::de::Error::custom)?;
+    Ok(Some(value * multiplier))
+}
+
+pub fn parse_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    Regex::new(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// A glob where `*` matches any run of characters, `/` included, and `?` any one character.
+pub fn parse_glob<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    let pattern = s
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect::<String>();
+    Regex::new(&format!("^{}$", pattern))
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+pub fn parse_optional_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_duration(deserializer).map(Some)
+}
@@ -1,21 +1,37 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
+use itertools::Itertools;
 use ratatui::{
     DefaultTerminal, Frame,
     crossterm::event,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style, Stylize},
     text::{Line, Span},
     widgets::{Block, Borders},
 };
+use remoteprocess::Pid;
 use tokio::sync::mpsc::Receiver;
 
 use crate::{
+    clock::{Clock, system_clock},
+    control::SamplerControl,
+    errors::AppError,
+    export::{ExportFormat, folded_stacks, write_folded},
+    health::{HealthMonitor, format_bytes},
+    launch::LaunchedProcess,
     priority::SpiedRecordQueueMap,
+    replay::ReplayControl,
     tabs::{
         StatefulWidgetExt,
         local_variables::{LocalVariableSelection, LocalVariableWidget},
+        pinned::PinnedWidget,
         terminal_event::UpdateEvent,
         thread_selection::{ThreadSelectionState, ThreadSelectionWidget},
         timeline::{TimelineWidget, ViewPortBounds},
@@ -33,12 +49,23 @@ pub enum Focus {
 #[derive(Debug)]
 pub struct AppState {
     focus: Focus,
-    thread_selection: ThreadSelectionState,
+    pub(super) thread_selection: ThreadSelectionState,
     pub(super) viewport_bound: ViewPortBounds,
     local_variable_state: LocalVariableSelection,
     pub record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>,
     running: bool,
     ratio: u16,
+    pub(super) replay: Option<ReplayControl>,
+    pub(super) sampler_control: Option<SamplerControl>,
+    pub(super) exports: Vec<(ExportFormat, PathBuf)>,
+    pub(super) folded_path: PathBuf,
+    pub(super) launched: Option<Arc<Mutex<LaunchedProcess>>>,
+    notice: Option<String>,
+    sampling_stopped: Option<(Instant, AppError)>,
+    // what is on screen while the view is frozen, as sampling carries on underneath
+    snapshot: Option<SpiedRecordQueueMap>,
+    clock: Arc<dyn Clock>,
+    health: HealthMonitor,
 }
 
 impl AppState {
@@ -46,6 +73,86 @@ impl AppState {
         self.running = false;
     }
 
+    /// Keeps what was sampled browsable after the sampler has given up.
+    pub(super) fn stop_sampling(&mut self, reason: AppError) {
+        let now = self.clock.now();
+        if self.snapshot.is_none() {
+            self.viewport_bound.freeze(now);
+        }
+        self.sampling_stopped = Some((now, reason));
+    }
+
+    /// Tells about one of several targets that stopped, while sampling carries on.
+    pub(super) fn target_stopped(&mut self, pid: Pid, reason: AppError) {
+        self.notice = Some(format!("{:08x}: {}", pid, reason));
+    }
+
+    fn toggle_freeze(&mut self) -> Result<(), Error> {
+        if self.snapshot.take().is_some() {
+            self.viewport_bound.thaw();
+            if let Some((stopped_at, _)) = &self.sampling_stopped {
+                self.viewport_bound.freeze(*stopped_at);
+            }
+        } else {
+            let now = self.clock.now();
+            self.snapshot = Some(
+                self.record_queue_map
+                    .read()
+                    .map_err(|_| AppError::SamplerSenderError)?
+                    .clone(),
+            );
+            self.viewport_bound
+                .freeze(self.sampling_stopped.as_ref().map_or(now, |(at, _)| *at));
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the queues currently on screen.
+    fn with_queues<R>(&self, f: impl FnOnce(&SpiedRecordQueueMap) -> R) -> Result<R, Error> {
+        match &self.snapshot {
+            Some(snapshot) => Ok(f(snapshot)),
+            None => Ok(f(&*self
+                .record_queue_map
+                .read()
+                .map_err(|_| AppError::SamplerSenderError)?)),
+        }
+    }
+
+    fn export(&self, format: ExportFormat, path: &Path) -> Result<(), Error> {
+        self.with_queues(|qmaps| format.write(qmaps, BufWriter::new(File::create(path)?)))?
+    }
+
+    pub(super) fn export_all(&self) -> Result<(), Error> {
+        for (format, path) in self.exports.iter() {
+            self.export(*format, path)?;
+        }
+        Ok(())
+    }
+
+    fn export_on_key(&mut self) {
+        let default = [(
+            ExportFormat::ChromeTrace,
+            PathBuf::from("fadetop_trace.json"),
+        )];
+        let exports = if self.exports.is_empty() {
+            &default[..]
+        } else {
+            &self.exports[..]
+        };
+        self.notice = Some(
+            match exports
+                .iter()
+                .try_for_each(|(format, path)| self.export(*format, path))
+            {
+                Ok(()) => format!(
+                    "Exported {}",
+                    exports.iter().map(|(_, path)| path.display()).join(", ")
+                ),
+                Err(err) => format!("Export failed: {}", err),
+            },
+        );
+    }
+
     pub async fn run_until_error(
         &mut self,
         mut terminal: DefaultTerminal,
@@ -63,6 +170,25 @@ impl AppState {
         Ok(())
     }
 
+    fn export_folded(&mut self, all_threads: bool) -> Result<(), Error> {
+        let (left, right) = self.viewport_bound.window();
+        let folded = self.with_queues(|qmaps| {
+            if all_threads {
+                folded_stacks(qmaps.iter().map(|(_, q)| q), left, right)
+            } else {
+                folded_stacks(self.thread_selection.select_thread(qmaps), left, right)
+            }
+        })?;
+        write_folded(&folded, BufWriter::new(File::create(&self.folded_path)?))
+    }
+
+    fn export_folded_on_key(&mut self, all_threads: bool) {
+        self.notice = Some(match self.export_folded(all_threads) {
+            Ok(()) => format!("Folded {}", self.folded_path.display()),
+            Err(err) => format!("Export failed: {}", err),
+        });
+    }
+
     pub fn new() -> Self {
         Self {
             focus: Focus::ThreadList,
@@ -72,14 +198,83 @@ impl AppState {
             local_variable_state: LocalVariableSelection::default(),
             running: true,
             ratio: 80,
+            replay: None,
+            sampler_control: None,
+            exports: Vec::new(),
+            folded_path: PathBuf::from("fadetop.folded"),
+            launched: None,
+            notice: None,
+            sampling_stopped: None,
+            snapshot: None,
+            clock: system_clock(),
+            health: HealthMonitor::default(),
         }
     }
 
+    /// Shares `clock` between the queues and the timeline.
+    pub(super) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        if let Ok(mut qmaps) = self.record_queue_map.write() {
+            qmaps.with_clock(Arc::clone(&clock));
+        }
+        self.viewport_bound = self.viewport_bound.clone().with_clock(Arc::clone(&clock));
+        self.clock = clock;
+    }
+
+    /// How the samplers keep up and what fadetop itself costs, measured on the live queues.
+    fn health_line(&mut self) -> Option<Line<'static>> {
+        let now = self.clock.now();
+        let report = self
+            .health
+            .report(&*self.record_queue_map.read().ok()?, now);
+        let mut spans = Vec::new();
+        if let Some(settings) = self.sampler_control.as_ref().and_then(|c| c.settings()) {
+            let expected = settings.sampling_rate * report.sampled_processes.max(1) as u64;
+            let behind = !settings.paused && report.samples_per_sec < 0.9 * expected as f64;
+            spans.push(
+                Span::from(format!(
+                    "{:.0}/{} samples/s",
+                    report.samples_per_sec, expected
+                ))
+                .style(if behind {
+                    Style::default().fg(Color::Black).bg(Color::Yellow)
+                } else {
+                    Style::default()
+                }),
+            );
+            spans.push(format!(" {} late {} dropped · ", report.late, report.errors).into());
+        }
+        spans.push(
+            format!(
+                "{:.1}% cpu {} records ≈{}",
+                report.cpu_percent,
+                report.records,
+                format_bytes(report.record_bytes)
+            )
+            .into(),
+        );
+        Some(Line::from(spans))
+    }
+
     fn render_full_app(&mut self, frame: &mut Frame) {
+        let health_line = self.health_line();
         let out_block = {
-            Block::default()
+            let mut block = Block::default()
                 .borders(Borders::NONE)
                 .title_top(Line::from("Esc").underlined().right_aligned())
+                .title_top(
+                    Line::from(vec![Span::from("E").underlined(), "xport".into()]).right_aligned(),
+                )
+                .title_top(
+                    Line::from(vec![Span::from("F").underlined(), "old".into()]).right_aligned(),
+                )
+                .title_top(
+                    Line::from(vec![
+                        "Free".into(),
+                        Span::from("z").underlined(),
+                        "e".into(),
+                    ])
+                    .right_aligned(),
+                )
                 .title_top(Line::from("Tab").underlined().left_aligned())
                 .title_top(
                     Line::from(vec![
@@ -91,10 +286,115 @@ impl AppState {
                     ])
                     .left_aligned(),
                 )
-                .title_style(Style::default().bg(Color::Rgb(0, 0, 100)))
+                .title_style(Style::default().bg(Color::Rgb(0, 0, 100)));
+            if let Some(status) = self.replay.as_ref().and_then(|r| r.status()) {
+                block = block.title_top(
+                    Line::from(vec![
+                        Span::from("Space").underlined(),
+                        if status.paused { " ⏸ " } else { " ▶ " }.into(),
+                        Span::from("-").underlined(),
+                        format!("{}", status.speed).into(),
+                        Span::from("+").underlined(),
+                        " ".into(),
+                        Span::from("<").underlined(),
+                        format!(
+                            "{:0>2}:{:0>2}/{:0>2}:{:0>2}",
+                            status.position.as_secs() / 60,
+                            status.position.as_secs() % 60,
+                            status.length.as_secs() / 60,
+                            status.length.as_secs() % 60
+                        )
+                        .into(),
+                        Span::from(">").underlined(),
+                    ])
+                    .centered(),
+                );
+            }
+            if let Some(settings) = self.sampler_control.as_ref().and_then(|c| c.settings()) {
+                let toggle = |on: bool| {
+                    if on {
+                        Style::default()
+                    } else {
+                        Style::default().crossed_out()
+                    }
+                };
+                block = block.title_top(
+                    Line::from(vec![
+                        Span::from("Space").underlined(),
+                        if settings.paused { " ⏸ " } else { " ▶ " }.into(),
+                        Span::from("-").underlined(),
+                        format!("{}Hz", settings.sampling_rate).into(),
+                        Span::from("+").underlined(),
+                        " ".into(),
+                        Span::from("N")
+                            .underlined()
+                            .patch_style(toggle(settings.native)),
+                        Span::from("ative").style(toggle(settings.native)),
+                        " ".into(),
+                        Span::from("L")
+                            .underlined()
+                            .patch_style(toggle(settings.dump_locals > 0)),
+                        Span::from("ocals").style(toggle(settings.dump_locals > 0)),
+                    ])
+                    .centered(),
+                );
+            }
+            if let Some(Ok(mut launched)) = self.launched.as_ref().map(|l| l.lock()) {
+                let status = match launched.poll() {
+                    Some(status) => format!("{} exited with {}", launched.command, status),
+                    None => format!("{} [{}]", launched.command, launched.pid()),
+                };
+                block = block.title_top(Line::from(status).left_aligned());
+            }
+            if let Some(notice) = &self.notice {
+                block = block.title_top(Line::from(notice.as_str()).right_aligned());
+            }
+            let evicted = self.with_queues(|qmaps| qmaps.evicted()).unwrap_or(0);
+            if evicted > 0 {
+                block = block.title_top(
+                    Line::from(format!("{} records evicted over budget", evicted))
+                        .style(Style::default().fg(Color::Black).bg(Color::Yellow))
+                        .left_aligned(),
+                );
+            }
+            let gap_count = self.with_queues(|qmaps| qmaps.gap_count()).unwrap_or(0);
+            if gap_count > 0 {
+                block = block.title_top(
+                    Line::from(format!(
+                        "{} gap{} in sampling",
+                        gap_count,
+                        if gap_count == 1 { "" } else { "s" }
+                    ))
+                    .style(Style::default().fg(Color::Black).bg(Color::Yellow))
+                    .left_aligned(),
+                );
+            }
+            if self.snapshot.is_some() {
+                block = block.title_top(
+                    Line::from(vec![
+                        "Frozen, ".into(),
+                        Span::from("z").underlined(),
+                        " to go live".into(),
+                    ])
+                    .style(Style::default().fg(Color::Black).bg(Color::Cyan))
+                    .centered(),
+                );
+            }
+            if let Some(health_line) = health_line {
+                block = block.title_bottom(health_line.right_aligned());
+            }
+            if let Some((_, reason)) = &self.sampling_stopped {
+                block = block.title_bottom(
+                    Line::from(format!("{}, the timeline is frozen", reason))
+                        .style(Style::default().fg(Color::White).bg(Color::Red))
+                        .left_aligned(),
+                );
+            }
+            block
         };
 
         let inner = out_block.inner(frame.area());
+        frame.render_widget(out_block, frame.area());
         let [timeline, right] = Layout::default()
             .direction(Direction::Horizontal)
             .constraints(vec![
@@ -102,54 +402,151 @@ impl AppState {
                 Constraint::Percentage(100 - self.ratio),
             ])
             .areas(inner);
-        let [tab_selector, locals] = Layout::default()
+
+        let areas = [timeline, right];
+        if let Some(snapshot) = self.snapshot.take() {
+            self.render_queues(frame, &snapshot, areas);
+            self.snapshot = Some(snapshot);
+        } else {
+            let record_queue_map = Arc::clone(&self.record_queue_map);
+            match record_queue_map.read() {
+                Ok(qmaps) => self.render_queues(frame, &qmaps, areas),
+                _ => {
+                    self.running = false;
+                }
+            }
+        }
+    }
+
+    fn render_queues(
+        &mut self,
+        frame: &mut Frame,
+        qmaps: &SpiedRecordQueueMap,
+        [timeline, right]: [Rect; 2],
+    ) {
+        // the pinned panel takes room from the others only once something is pinned
+        let pinned = PinnedWidget::from_queues(qmaps);
+        let pinned_height = if pinned.is_empty() {
+            0
+        } else {
+            (pinned.len() as u16 + 2).min(right.height / 3)
+        };
+        let [tab_selector, locals, pinned_area] = Layout::default()
             .direction(Direction::Vertical)
-            .constraints(vec![Constraint::Fill(1), Constraint::Fill(1)])
+            .constraints(vec![
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+                Constraint::Length(pinned_height),
+            ])
             .areas(right);
+        if !pinned.is_empty() {
+            frame.render_widget(pinned, pinned_area);
+        }
 
-        match self.record_queue_map.read() {
-            Ok(qmaps) => {
-                self.thread_selection.update_threads(&qmaps);
-                frame.render_stateful_widget(
-                    ThreadSelectionWidget {
-                        focused: self.focus == Focus::ThreadList,
-                    }
-                    .blocked(),
-                    tab_selector,
-                    &mut self.thread_selection,
-                );
-                let queue = self.thread_selection.select_thread(&qmaps);
-                frame.render_stateful_widget(
-                    TimelineWidget::from_queue(queue)
-                        .focused(self.focus == Focus::Timeline)
-                        .blocked(),
-                    timeline,
-                    &mut self.viewport_bound,
-                );
-                frame.render_stateful_widget(
-                    LocalVariableWidget::from_queue(
-                        queue,
-                        self.viewport_bound.selected_depth as usize,
-                    )
-                    .focused(self.focus == Focus::LogView)
-                    .blocked(),
-                    locals,
-                    &mut self.local_variable_state,
-                );
+        self.thread_selection.update_threads(qmaps);
+        frame.render_stateful_widget(
+            ThreadSelectionWidget {
+                focused: self.focus == Focus::ThreadList,
             }
-            _ => {
-                self.running = false;
+            .blocked(),
+            tab_selector,
+            &mut self.thread_selection,
+        );
+        let queue = self.thread_selection.select_thread(qmaps);
+        frame.render_stateful_widget(
+            TimelineWidget::from_queue(queue)
+                .focused(self.focus == Focus::Timeline)
+                .blocked(),
+            timeline,
+            &mut self.viewport_bound,
+        );
+        frame.render_stateful_widget(
+            LocalVariableWidget::from_queue(queue, self.viewport_bound.selected_depth as usize)
+                .focused(self.focus == Focus::LogView)
+                .blocked(),
+            locals,
+            &mut self.local_variable_state,
+        );
+    }
+
+    fn step_selected_event(&mut self, forward: bool) -> Result<(), Error> {
+        let record_queue_map = Arc::clone(&self.record_queue_map);
+        let live = record_queue_map
+            .read()
+            .map_err(|_| AppError::SamplerSenderError)?;
+        let qmaps = self.snapshot.as_ref().unwrap_or(&*live);
+        if let Some(queue) = self.thread_selection.select_thread(qmaps) {
+            self.viewport_bound.step_selected_event(queue, forward);
+        }
+        Ok(())
+    }
+
+    /// Pins the selected event in the live queues, and in the frozen view if there is one.
+    fn pin_selected_event(&mut self) -> Result<(), Error> {
+        let Some(start) = self.viewport_bound.selected_event else {
+            return Ok(());
+        };
+        let depth = self.viewport_bound.selected_depth as usize;
+        let Some((key, fqn)) = self.with_queues(|qmaps| {
+            let queue = self.thread_selection.select_thread(qmaps)?;
+            let record = queue.finished_at(depth, start)?;
+            Some((
+                (queue.thread_info.pid, queue.thread_info.tid),
+                record.frame_key.fqn(),
+            ))
+        })?
+        else {
+            return Ok(());
+        };
+        let mut pinned = self
+            .record_queue_map
+            .write()
+            .map_err(|_| AppError::SamplerSenderError)?
+            .pin(&key, depth, start);
+        if let Some(snapshot) = self.snapshot.as_mut() {
+            pinned |= snapshot.pin(&key, depth, start);
+        }
+        if pinned {
+            self.notice = Some(format!("Pinned {}", fqn));
+        }
+        Ok(())
+    }
+
+    fn handle_replay_event(&mut self, key: &event::KeyEvent) {
+        if let Some(replay) = self.replay.as_ref() {
+            let step = self.viewport_bound.width / 4;
+            match key.code {
+                event::KeyCode::Char(' ') => replay.toggle_pause(),
+                event::KeyCode::Char('+') => replay.faster(),
+                event::KeyCode::Char('-') => replay.slower(),
+                event::KeyCode::Char('<') => replay.seek_backward(step.max(Duration::from_secs(1))),
+                event::KeyCode::Char('>') => replay.seek_forward(step.max(Duration::from_secs(1))),
+                _ => {}
             }
         }
+    }
 
-        frame.render_widget(out_block, frame.area());
+    fn handle_sampler_event(&mut self, key: &event::KeyEvent) {
+        if let Some(control) = self.sampler_control.as_ref() {
+            match key.code {
+                event::KeyCode::Char(' ') => control.toggle_pause(),
+                event::KeyCode::Char('+') => control.faster(),
+                event::KeyCode::Char('-') => control.slower(),
+                event::KeyCode::Char('n') => control.toggle_native(),
+                event::KeyCode::Char('l') => control.toggle_locals(),
+                _ => {}
+            }
+        }
     }
 
     pub fn handle_crossterm_events(&mut self, term_event: event::Event) -> Result<(), Error> {
         match term_event {
             event::Event::Key(key) => match (key.modifiers, key.code) {
                 // Global shortcuts
-                (_, event::KeyCode::Esc) => Ok(self.quit()),
+                (_, event::KeyCode::Esc) => {
+                    self.quit();
+                    Ok(())
+                }
                 (_, event::KeyCode::Tab) => {
                     self.focus = match self.focus {
                         Focus::ThreadList => Focus::Timeline,
@@ -158,22 +555,58 @@ impl AppState {
                     };
                     Ok(())
                 }
+                (_, event::KeyCode::Char('e')) => {
+                    self.export_on_key();
+                    Ok(())
+                }
+                (_, event::KeyCode::Char('f')) => {
+                    self.export_folded_on_key(false);
+                    Ok(())
+                }
+                (_, event::KeyCode::Char('F')) => {
+                    self.export_folded_on_key(true);
+                    Ok(())
+                }
+                (_, event::KeyCode::Char('z')) => self.toggle_freeze(),
+                (_, event::KeyCode::Char('[')) if self.focus == Focus::Timeline => {
+                    self.step_selected_event(false)
+                }
+                (_, event::KeyCode::Char(']')) if self.focus == Focus::Timeline => {
+                    self.step_selected_event(true)
+                }
+                (_, event::KeyCode::Char('p')) if self.focus == Focus::Timeline => {
+                    self.pin_selected_event()
+                }
                 (_, event::KeyCode::Char('i') | event::KeyCode::Char('o')) => {
-                    Ok(self.viewport_bound.handle_zoom_event(&key))
+                    self.viewport_bound.handle_zoom_event(&key);
+                    Ok(())
+                }
+                (_, event::KeyCode::Char(' ' | '+' | '-' | '<' | '>')) if self.replay.is_some() => {
+                    self.handle_replay_event(&key);
+                    Ok(())
+                }
+                (_, event::KeyCode::Char(' ' | '+' | '-' | 'n' | 'l'))
+                    if self.sampler_control.is_some() =>
+                {
+                    self.handle_sampler_event(&key);
+                    Ok(())
                 }
                 (event::KeyModifiers::CONTROL, event::KeyCode::Right) => {
-                    Ok(self.ratio = (self.ratio + 1).min(100))
+                    self.ratio = (self.ratio + 1).min(100);
+                    Ok(())
                 }
                 (event::KeyModifiers::CONTROL, event::KeyCode::Left) => {
-                    Ok(self.ratio = self.ratio.saturating_sub(1))
+                    self.ratio = self.ratio.saturating_sub(1);
+                    Ok(())
                 }
-                _ => Ok({
+                _ => {
                     match self.focus {
                         Focus::ThreadList => self.thread_selection.handle_focused_event(&key),
                         Focus::Timeline => self.viewport_bound.handle_focused_event(&key),
                         Focus::LogView => self.local_variable_state.handle_focused_event(&key),
                     }
-                }),
+                    Ok(())
+                }
             },
             _ => Ok(()),
         }
@@ -11,7 +11,7 @@ use ratatui::{
     },
 };
 
-use crate::priority::SpiedRecordQueue;
+use crate::priority::{SampleCounts, SpiedRecordQueue};
 
 use super::{StatefulWidgetExt, get_scroll};
 
@@ -60,6 +60,7 @@ impl LocalVariableSelection {
 
 pub struct LocalVariableWidget<'a> {
     locals: Option<&'a Vec<LocalVariable>>,
+    counts: Option<SampleCounts>,
     focused: bool,
 }
 
@@ -72,11 +73,13 @@ impl<'a> LocalVariableWidget<'a> {
         if let Some(record) = queue.and_then(|q| q.unfinished_events.get(selected_depth)) {
             Self {
                 locals: record.locals(),
+                counts: Some(record.counts),
                 focused: false,
             }
         } else {
             Self {
                 locals: None,
+                counts: None,
                 focused: false,
             }
         }
@@ -97,7 +100,7 @@ impl StatefulWidget for LocalVariableWidget<'_> {
                     .render(local_section, buf);
                 };
             } else {
-                state.scroll_offset.0 = state.scroll_offset.0 % (locals.len() as u16).max(1);
+                state.scroll_offset.0 %= (locals.len() as u16).max(1);
                 StatefulWidget::render(
                     Table::new(
                         locals
@@ -124,8 +127,7 @@ impl StatefulWidget for LocalVariableWidget<'_> {
                 if self.focused {
                     buf.cell_mut((
                         local_section.left() - 1,
-                        local_section.top()
-                            + (state.scroll_offset.0 as u16) % local_section.height.max(1),
+                        local_section.top() + state.scroll_offset.0 % local_section.height.max(1),
                     ))
                     .map(|cell| cell.set_char('↕'));
                 }
@@ -135,11 +137,24 @@ impl StatefulWidget for LocalVariableWidget<'_> {
 }
 
 impl StatefulWidgetExt for LocalVariableWidget<'_> {
-    fn get_block(&self, state: &mut Self::State) -> Block {
-        let block = Block::default()
+    fn get_block(&self, state: &mut Self::State) -> Block<'_> {
+        let mut block = Block::default()
             .title(Line::from("Live Stack").bold().left_aligned())
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded);
+        if let Some(counts) = self.counts {
+            let percent = |count: u64| count * 100 / counts.samples.max(1);
+            block = block.title(
+                Line::from(format!(
+                    "{} sample{} {}% active {}% GIL",
+                    counts.samples,
+                    if counts.samples == 1 { "" } else { "s" },
+                    percent(counts.active),
+                    percent(counts.owns_gil)
+                ))
+                .right_aligned(),
+            );
+        }
 
         if self.focused {
             block
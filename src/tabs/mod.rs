@@ -5,6 +5,8 @@ use ratatui::{
 };
 
 pub mod local_variables;
+pub mod pinned;
+pub mod process_selection;
 pub mod terminal_event;
 pub mod thread_selection;
 pub mod timeline;
@@ -23,11 +25,11 @@ impl<W: Widget> Widget for Blocked<W> {
 }
 
 pub trait StatefulWidgetExt: StatefulWidget + Sized {
-    fn get_block(&self, _state: &mut Self::State) -> Block {
+    fn get_block(&self, _state: &mut Self::State) -> Block<'_> {
         Default::default()
     }
 
-    fn blocked<'b>(self) -> Blocked<Self> {
+    fn blocked(self) -> Blocked<Self> {
         Blocked { sub: self }
     }
 }
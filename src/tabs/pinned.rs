@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use itertools::Itertools;
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::Stylize,
+    text::Line,
+    widgets::{Block, BorderType, Borders, Paragraph, Widget},
+};
+
+use crate::priority::SpiedRecordQueueMap;
+
+struct PinnedEvent {
+    thread: String,
+    fqn: String,
+    since_start: Duration,
+    duration: Duration,
+}
+
+/// Lists the events pinned across all threads, in the order they started.
+pub struct PinnedWidget {
+    events: Vec<PinnedEvent>,
+}
+
+impl PinnedWidget {
+    pub fn from_queues(qmaps: &SpiedRecordQueueMap) -> Self {
+        Self {
+            events: qmaps
+                .iter()
+                .flat_map(|(_, queue)| {
+                    queue.pinned().iter().map(|record| PinnedEvent {
+                        thread: match queue.thread_name() {
+                            Some(name) => name.clone(),
+                            None => format!("{:08x}", queue.thread_info.tid),
+                        },
+                        fqn: record.frame_key.fqn(),
+                        since_start: qmaps.since_start(record.start),
+                        duration: record.end - record.start,
+                    })
+                })
+                .sorted_by_key(|event| event.since_start)
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+}
+
+impl Widget for PinnedWidget {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        Paragraph::new(
+            self.events
+                .iter()
+                .map(|event| {
+                    Line::from(vec![
+                        format!(
+                            "+{:0>2}:{:0>2} ",
+                            event.since_start.as_secs() / 60,
+                            event.since_start.as_secs() % 60
+                        )
+                        .into(),
+                        format!("{:.1?} ", event.duration).bold(),
+                        format!("[{}] ", event.thread).dark_gray(),
+                        event.fqn.clone().into(),
+                    ])
+                })
+                .collect::<Vec<Line>>(),
+        )
+        .block(
+            Block::default()
+                .title(Line::from("Pinned").bold().left_aligned())
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded),
+        )
+        .render(area, buf);
+    }
+}
@@ -0,0 +1,130 @@
+use ratatui::{
+    buffer::Buffer,
+    crossterm::event::{self, KeyEvent},
+    layout::Rect,
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, BorderType, Borders, Paragraph, StatefulWidget, Widget},
+};
+use remoteprocess::Pid;
+
+use crate::discover::PythonProcess;
+
+use super::{StatefulWidgetExt, get_scroll};
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessSelectionState {
+    processes: Vec<PythonProcess>,
+    filter: String,
+    selected_index: usize,
+}
+
+pub enum ProcessSelectionOutcome {
+    Pending,
+    Selected(Pid),
+    Cancelled,
+}
+
+impl ProcessSelectionState {
+    pub fn update_processes(&mut self, processes: Vec<PythonProcess>) {
+        let selected_pid = self.filtered().get(self.selected_index).map(|p| p.pid);
+        self.processes = processes;
+        self.selected_index = selected_pid
+            .and_then(|pid| self.filtered().iter().position(|p| p.pid == pid))
+            .unwrap_or(self.selected_index);
+    }
+
+    fn matches(&self, process: &PythonProcess) -> bool {
+        let filter = self.filter.to_lowercase();
+        process.command_line.to_lowercase().contains(&filter)
+            || process.pid.to_string().contains(&filter)
+            || process
+                .user
+                .as_ref()
+                .is_some_and(|user| user.to_lowercase().contains(&filter))
+    }
+
+    fn filtered(&self) -> Vec<&PythonProcess> {
+        self.processes.iter().filter(|p| self.matches(p)).collect()
+    }
+
+    pub fn handle_focused_event(&mut self, key: &KeyEvent) -> ProcessSelectionOutcome {
+        match key.code {
+            event::KeyCode::Esc => return ProcessSelectionOutcome::Cancelled,
+            event::KeyCode::Enter => {
+                if let Some(process) = self.filtered().get(self.selected_index) {
+                    return ProcessSelectionOutcome::Selected(process.pid);
+                }
+            }
+            event::KeyCode::Down => self.selected_index = self.selected_index.saturating_add(1),
+            event::KeyCode::Up => self.selected_index = self.selected_index.saturating_sub(1),
+            event::KeyCode::Backspace => {
+                self.filter.pop();
+                self.selected_index = 0;
+            }
+            event::KeyCode::Char(c) => {
+                self.filter.push(c);
+                self.selected_index = 0;
+            }
+            _ => {}
+        }
+        ProcessSelectionOutcome::Pending
+    }
+}
+
+pub struct ProcessSelectionWidget {}
+
+impl StatefulWidget for ProcessSelectionWidget {
+    type State = ProcessSelectionState;
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        if area.is_empty() {
+            return;
+        }
+
+        state.selected_index = state
+            .selected_index
+            .min(state.filtered().len().saturating_sub(1));
+        let filtered = state.filtered();
+
+        let process_lines = filtered
+            .iter()
+            .enumerate()
+            .map(|(i, process)| {
+                let text = format!(
+                    "{:>8} {:<10} {:<6} {}",
+                    process.pid,
+                    process.user.as_deref().unwrap_or("?"),
+                    process.python_version.as_deref().unwrap_or(""),
+                    process.command_line
+                );
+                if i == state.selected_index {
+                    Line::from(format!("❯{}", text)).bg(Color::Blue)
+                } else {
+                    Line::from(format!(" {}", text))
+                }
+            })
+            .collect::<Vec<Line>>();
+
+        Paragraph::new(process_lines)
+            .scroll((get_scroll(state.selected_index as u16, area.height), 0))
+            .render(area, buf);
+    }
+}
+
+impl StatefulWidgetExt for ProcessSelectionWidget {
+    fn get_block(&self, state: &mut Self::State) -> Block<'_> {
+        Block::default()
+            .title("Python Processes")
+            .title(
+                Line::from(vec![
+                    Span::from(" Filter: "),
+                    Span::from(state.filter.clone()).underlined(),
+                ])
+                .left_aligned(),
+            )
+            .title_bottom(Line::from("Enter to attach, Esc to quit").right_aligned())
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(Style::new().blue().on_dark_gray().bold().italic())
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Error;
 use ratatui::crossterm;
+use remoteprocess::Pid;
 
 use crate::{errors::AppError, state::AppState};
 
@@ -7,6 +8,8 @@ pub enum UpdateEvent {
     Periodic,
     Input(crossterm::event::Event),
     Error(AppError),
+    /// One of several targets stopped being sampled, while the others carry on.
+    TargetStopped(Pid, AppError),
 }
 
 impl UpdateEvent {
@@ -14,6 +17,14 @@ impl UpdateEvent {
         match self {
             UpdateEvent::Input(term_event) => app_state.handle_crossterm_events(term_event),
             UpdateEvent::Periodic => Ok(()),
+            UpdateEvent::TargetStopped(pid, reason) => {
+                app_state.target_stopped(pid, reason);
+                Ok(())
+            }
+            UpdateEvent::Error(err) if err.is_recoverable() => {
+                app_state.stop_sampling(err);
+                Ok(())
+            }
             UpdateEvent::Error(err) => Err(err.into()),
         }
     }
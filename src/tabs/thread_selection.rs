@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use itertools::Itertools;
 use ratatui::{
     buffer::Buffer,
@@ -17,7 +19,9 @@ use super::{StatefulWidgetExt, get_scroll};
 pub struct ThreadSelectionState {
     selected_thread_index: (usize, usize),
     available_threads: Vec<(Pid, Vec<ThreadInfo>)>,
+    exited_processes: HashSet<Pid>,
     show_processes: bool,
+    pub(crate) hide_idle: bool,
 }
 
 impl Default for ThreadSelectionState {
@@ -25,7 +29,9 @@ impl Default for ThreadSelectionState {
         Self {
             selected_thread_index: (0, 0),
             available_threads: Vec::new(),
+            exited_processes: HashSet::new(),
             show_processes: true,
+            hide_idle: false,
         }
     }
 }
@@ -62,18 +68,26 @@ impl ThreadSelectionState {
         &self,
         queues: &'a SpiedRecordQueueMap,
     ) -> Option<&'a SpiedRecordQueue> {
-        queues.get(&self.get_selected_pt().1?)
+        let (pid, tid) = self.get_selected_pt();
+        queues.get(&(pid?, tid?))
     }
 
     pub fn update_threads(&mut self, qmaps: &SpiedRecordQueueMap) {
         let (maybe_pid, maybe_tid) = self.get_selected_pt();
         self.available_threads = qmaps
             .iter()
+            .filter(|(_, q)| !(self.hide_idle && q.is_idle()))
             .map(|(_, q)| q.thread_info.clone())
             .into_group_map_by(|info| info.pid)
             .into_iter()
             .sorted_by(|(pid1, _), (pid2, _)| pid1.cmp(pid2))
             .collect();
+        self.exited_processes = self
+            .available_threads
+            .iter()
+            .map(|(pid, _)| *pid)
+            .filter(|pid| qmaps.is_exited(*pid))
+            .collect();
 
         let (pi, ti) = &mut self.selected_thread_index;
 
@@ -115,15 +129,15 @@ impl StatefulWidget for ThreadSelectionWidget {
             let mut process_lines = Vec::new();
 
             for (i, (pid, tinfos)) in state.available_threads.iter().enumerate() {
-                if i == state.selected_thread_index.0 {
-                    process_lines.push(
-                        Line::from(format!("{:08x}({:})❯", pid, tinfos.len())).bg(Color::Blue),
-                    );
+                let exited = state.exited_processes.contains(pid);
+                let line = if i == state.selected_thread_index.0 {
+                    Line::from(format!("{:08x}({:})❯", pid, tinfos.len())).bg(Color::Blue)
+                } else if exited {
+                    Line::from(format!("{:08x}({:})", pid, tinfos.len())).bg(Color::DarkGray)
                 } else {
-                    process_lines.push(
-                        Line::from(format!("{:08x}({:})", pid, tinfos.len())).bg(Color::Green),
-                    );
-                }
+                    Line::from(format!("{:08x}({:})", pid, tinfos.len())).bg(Color::Green)
+                };
+                process_lines.push(if exited { line.crossed_out() } else { line });
             }
             Paragraph::new(process_lines)
                 .scroll((
@@ -146,7 +160,7 @@ impl StatefulWidget for ThreadSelectionWidget {
 
         let (pi, ti) = state.selected_thread_index;
 
-        state.available_threads.get(pi).map(|(_, thread_infos)| {
+        if let Some((_, thread_infos)) = state.available_threads.get(pi) {
             let thread_lines = thread_infos
                 .iter()
                 .enumerate()
@@ -172,12 +186,12 @@ impl StatefulWidget for ThreadSelectionWidget {
                 .block(Block::new())
                 .scroll((get_scroll(ti as u16, area.height), 0))
                 .render(threads_tab, buf);
-        });
+        }
     }
 }
 
 impl StatefulWidgetExt for ThreadSelectionWidget {
-    fn get_block(&self, state: &mut Self::State) -> Block {
+    fn get_block(&self, state: &mut Self::State) -> Block<'_> {
         let mut block = Block::default()
             .title("Threads")
             .borders(Borders::ALL)
@@ -201,7 +215,13 @@ impl StatefulWidgetExt for ThreadSelectionWidget {
                     state
                         .available_threads
                         .get(state.selected_thread_index.0)
-                        .and_then(|(pid, _)| format!("{:08x}", pid).into())
+                        .map(|(pid, _)| {
+                            if state.exited_processes.contains(pid) {
+                                format!("{:08x} exited", pid)
+                            } else {
+                                format!("{:08x}", pid)
+                            }
+                        })
                         .unwrap_or_default(),
                 )
                 .left_aligned(),
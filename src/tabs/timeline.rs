@@ -1,5 +1,6 @@
 use std::{
-    ops::{DivAssign, MulAssign},
+    ops::{DivAssign, MulAssign, Range},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
@@ -8,11 +9,12 @@ use ratatui::{
     crossterm::event::{self, KeyEvent},
     layout::Rect,
     style::{Color, Style, Stylize},
-    text::Line,
+    text::{Line, Span},
     widgets::{Block, BorderType, Borders, StatefulWidget},
 };
 
-use crate::priority::SpiedRecordQueue;
+use crate::clock::{Clock, system_clock};
+use crate::priority::{Activity, Aggregate, SampleCounts, SpiedRecordQueue};
 
 use super::{StatefulWidgetExt, get_scroll};
 
@@ -22,11 +24,15 @@ enum ViewPortRight {
     Selected(Instant),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct ViewPortBounds {
     right: ViewPortRight,
+    frozen_at: Option<Instant>,
+    clock: Arc<dyn Clock>,
     pub(crate) width: Duration,
     pub(crate) selected_depth: u16,
+    /// Start of the finished event selected at `selected_depth`.
+    pub(crate) selected_event: Option<Instant>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -46,8 +52,11 @@ impl Default for ViewPortBounds {
     fn default() -> Self {
         Self {
             right: ViewPortRight::Latest,
+            frozen_at: None,
+            clock: system_clock(),
             width: Duration::from_secs(60),
             selected_depth: 0,
+            selected_event: None,
         }
     }
 }
@@ -61,10 +70,39 @@ impl ViewPortBounds {
         self.width.div_assign(2);
     }
 
+    /// Stops the timeline at `at`, e.g. once nothing is being sampled any more.
+    pub(crate) fn freeze(&mut self, at: Instant) {
+        self.frozen_at = Some(at);
+    }
+
+    /// Follows the latest samples again.
+    pub(crate) fn thaw(&mut self) {
+        self.frozen_at = None;
+        self.right = ViewPortRight::Latest;
+    }
+
+    pub(crate) fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// The time at the right edge of a view that follows the latest samples.
+    pub(crate) fn now(&self) -> Instant {
+        self.frozen_at.unwrap_or_else(|| self.clock.now())
+    }
+
+    /// The time range currently on screen.
+    pub(crate) fn window(&self) -> (Instant, Instant) {
+        let right = match self.right {
+            ViewPortRight::Latest => self.now(),
+            ViewPortRight::Selected(right) => right,
+        };
+        (right - self.width, right)
+    }
+
     fn move_left(&mut self) {
         match self.right {
             ViewPortRight::Latest => {
-                self.right = ViewPortRight::Selected(Instant::now() - self.width / 2);
+                self.right = ViewPortRight::Selected(self.now() - self.width / 2);
             }
             ViewPortRight::Selected(right) => {
                 self.right = ViewPortRight::Selected(right - self.width / 2);
@@ -75,7 +113,7 @@ impl ViewPortBounds {
     fn move_right(&mut self) {
         match self.right {
             ViewPortRight::Latest => {
-                self.right = ViewPortRight::Selected(Instant::now() + self.width / 2);
+                self.right = ViewPortRight::Selected(self.now() + self.width / 2);
             }
             ViewPortRight::Selected(right) => {
                 self.right = ViewPortRight::Selected(right + self.width / 2);
@@ -86,11 +124,35 @@ impl ViewPortBounds {
     fn move_up(&mut self) {
         if self.selected_depth > 0 {
             self.selected_depth -= 1;
+            self.selected_event = None;
         }
     }
 
     fn move_down(&mut self) {
         self.selected_depth += 1;
+        self.selected_event = None;
+    }
+
+    /// Selects the finished event before, or after, the selected one at the selected depth,
+    /// starting from the edge of the window, and brings it into view if it is off screen.
+    pub(crate) fn step_selected_event(&mut self, queue: &SpiedRecordQueue, forward: bool) {
+        let depth = self.selected_depth as usize;
+        let (left, right) = self.window();
+        let next = match (self.selected_event, forward) {
+            (Some(start), false) => queue.finished_before(depth, start),
+            (Some(start), true) => queue.finished_after(depth, start),
+            (None, false) => queue.finished_before(depth, right),
+            (None, true) => queue
+                .finished_before(depth, left)
+                .filter(|record| record.end > left)
+                .or_else(|| queue.finished_after(depth, left)),
+        };
+        if let Some(record) = next {
+            self.selected_event = Some(record.start);
+            if record.end <= left || record.start >= right {
+                self.right = ViewPortRight::Selected(record.end.max(record.start + self.width / 2));
+            }
+        }
     }
 
     pub fn handle_zoom_event(&mut self, key: &KeyEvent) {
@@ -126,14 +188,8 @@ impl<'q> TimelineWidget<'q> {
     }
 
     fn max_depth(&self) -> usize {
-        self.queue.map_or(0, |q| {
-            q.finished_events
-                .iter()
-                .map(|r| r.depth)
-                .max()
-                .unwrap_or(0)
-                .max(q.unfinished_events.len())
-        })
+        self.queue
+            .map_or(0, |q| q.max_finished_depth().max(q.unfinished_events.len()))
     }
 
     pub fn focused(self, focused: bool) -> Self {
@@ -142,10 +198,10 @@ impl<'q> TimelineWidget<'q> {
 }
 
 impl StatefulWidgetExt for TimelineWidget<'_> {
-    fn get_block(&self, viewport_bound: &mut Self::State) -> Block {
-        let now = Instant::now();
+    fn get_block(&self, viewport_bound: &mut Self::State) -> Block<'_> {
+        let now = viewport_bound.now();
 
-        Block::default()
+        let mut block = Block::default()
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded)
             .border_style(if self.focused {
@@ -182,7 +238,21 @@ impl StatefulWidgetExt for TimelineWidget<'_> {
                     self.max_depth()
                 ))
                 .right_aligned(),
-            )
+            );
+        if self.focused {
+            block = block.title_bottom(
+                Line::from(vec![
+                    Span::from("[").underlined(),
+                    "/".into(),
+                    Span::from("]").underlined(),
+                    " select ".into(),
+                    Span::from("p").underlined(),
+                    "in".into(),
+                ])
+                .centered(),
+            );
+        }
+        block
     }
 }
 
@@ -194,11 +264,11 @@ impl<'q> StatefulWidget for TimelineWidget<'q> {
             return;
         }
         if let Some(queue) = self.queue {
-            let now = Instant::now();
-            if let ViewPortRight::Selected(end) = state.right {
-                if end > now {
-                    state.right = ViewPortRight::Latest;
-                }
+            let now = state.now();
+            if let ViewPortRight::Selected(end) = state.right
+                && end > now
+            {
+                state.right = ViewPortRight::Latest;
             }
             let visible_end = match state.right {
                 ViewPortRight::Selected(end) => end,
@@ -212,17 +282,27 @@ impl<'q> StatefulWidget for TimelineWidget<'q> {
             };
 
             let mut lines = Vec::new();
-            queue.finished_events.iter().for_each(|record| {
-                {
+            let first_depth = get_scroll(bound.selected_depth, area.height) as usize;
+            queue
+                .finished_in(
+                    first_depth..first_depth + area.height as usize,
+                    bound.left(),
+                    bound.right,
+                )
+                .for_each(|record| {
                     lines.push(FrameLine {
                         start: record.start,
                         end: record.end,
                         depth: record.depth as u16,
                         name: &record.frame_key.name,
+                        counts: record.counts,
+                        aggregate: record.aggregate,
+                        pinned: record.pinned,
+                        selected: record.depth == state.selected_depth as usize
+                            && Some(record.start) == state.selected_event,
                         running: false,
                     })
-                }
-            });
+                });
 
             queue
                 .unfinished_events
@@ -234,6 +314,10 @@ impl<'q> StatefulWidget for TimelineWidget<'q> {
                         end: queue.last_update,
                         depth: depth as u16,
                         name: &record.frame_key.name,
+                        counts: record.counts,
+                        aggregate: None,
+                        pinned: false,
+                        selected: false,
                         running: true,
                     });
                 });
@@ -241,11 +325,26 @@ impl<'q> StatefulWidget for TimelineWidget<'q> {
             for line in lines {
                 line.render_line(area, buf, bound);
             }
+            for gap in queue.gaps.iter() {
+                render_gap(gap, area, buf, bound);
+            }
 
-            let footer = self
-                .queue
-                .and_then(|q| q.unfinished_events.get(state.selected_depth as usize))
-                .map_or(Default::default(), |r| r.frame_key.fqn().clone());
+            let footer = match state
+                .selected_event
+                .and_then(|start| queue.finished_at(state.selected_depth as usize, start))
+            {
+                Some(record) => format!(
+                    "{} {:.1?} {} sample{}",
+                    record.frame_key.fqn(),
+                    record.end - record.start,
+                    record.counts.samples,
+                    if record.counts.samples == 1 { "" } else { "s" },
+                ),
+                None => queue
+                    .unfinished_events
+                    .get(state.selected_depth as usize)
+                    .map_or(Default::default(), |r| r.frame_key.fqn()),
+            };
 
             buf.set_span(area.left(), area.bottom(), &footer.into(), area.width);
 
@@ -260,22 +359,68 @@ impl<'q> StatefulWidget for TimelineWidget<'q> {
     }
 }
 
+// Nothing is known about what ran during a gap, so it covers every depth.
+fn render_gap(gap: &Range<Instant>, inner: Rect, buf: &mut Buffer, bound: ConcreteViewPort) {
+    if bound.width.is_zero() || gap.start >= bound.right || gap.end <= bound.left() {
+        return;
+    }
+    let tab_width = inner.width as f64;
+    let column = |at: Instant| {
+        ((at - bound.left()).div_duration_f64(bound.width) * tab_width).min(tab_width) as u16
+    };
+    let (from, to) = (column(gap.start), column(gap.end));
+    let style = Style::default().fg(Color::DarkGray).bg(Color::Reset);
+
+    for y in inner.top()..inner.bottom() {
+        for x in inner.left() + from..inner.left() + to {
+            buf[(x, y)].set_char('╱').set_style(style);
+        }
+    }
+    let label = "no data";
+    if to - from >= label.len() as u16 {
+        buf.set_string(
+            inner.left() + from + (to - from - label.len() as u16) / 2,
+            inner.top() + inner.height / 2,
+            label,
+            style,
+        );
+    }
+}
+
 struct FrameLine<'a> {
     start: Instant,
     end: Instant,
     depth: u16,
     name: &'a str,
+    counts: SampleCounts,
+    aggregate: Option<Aggregate>,
+    pinned: bool,
+    selected: bool,
     running: bool,
 }
 
+// Events seen in no more samples than this last mostly as long as the sampling happened to say.
+const LOW_CONFIDENCE_SAMPLES: u64 = 2;
+
+fn shade(color: Color, factor: f32) -> Color {
+    match color {
+        Color::Rgb(r, g, b) => Color::Rgb(
+            (r as f32 * factor) as u8,
+            (g as f32 * factor) as u8,
+            (b as f32 * factor) as u8,
+        ),
+        color => color,
+    }
+}
+
 impl FrameLine<'_> {
     fn color(&self) -> Color {
         if self.running {
-            return Color::Rgb(
+            Color::Rgb(
                 0,
                 150 - ((self.depth % 8 * 16) as u8),
                 200 - ((self.depth % 8 * 16) as u8),
-            );
+            )
         } else {
             let mut hash: u64 = 0xcbf29ce484222325;
             for byte in self.name.bytes() {
@@ -308,6 +453,25 @@ impl FrameLine<'_> {
         }
     }
 
+    fn style(&self) -> Style {
+        if self.counts.samples <= LOW_CONFIDENCE_SAMPLES {
+            return Style::default()
+                .fg(self.color())
+                .bg(shade(self.color(), 0.3))
+                .dim();
+        }
+        // idle and GIL-less time is darker than busy time, so the busy parts stand out
+        match self.counts.activity() {
+            Activity::Busy => Style::default().fg(Color::White).bg(self.color()),
+            Activity::WithoutGil => Style::default()
+                .fg(Color::White)
+                .bg(shade(self.color(), 0.7)),
+            Activity::Idle => Style::default()
+                .fg(Color::Gray)
+                .bg(shade(self.color(), 0.4)),
+        }
+    }
+
     fn render_line(self, inner: Rect, buf: &mut Buffer, bound: ConcreteViewPort) {
         let window_width = bound.width;
         if window_width.is_zero() || inner.height == 0 {
@@ -332,18 +496,242 @@ impl FrameLine<'_> {
             // choosing line continuity over translational invariance of block width
             let block_width = relative_end as usize - relative_start as usize;
 
-            let padded_string = format!(
-                "{:^block_width$}",
-                self.name.chars().take(block_width).collect::<String>(),
-                block_width = block_width
-            );
+            // merged blocks are hatched and say how much of them their frame took up
+            let padded_string = match self.aggregate {
+                Some(aggregate) => format!(
+                    "{:░^block_width$}",
+                    format!(
+                        "{}×{} {:.0}%",
+                        aggregate.calls,
+                        self.name,
+                        aggregate
+                            .busy
+                            .div_duration_f64(self.end - self.start)
+                            .min(1.0)
+                            * 100.0
+                    )
+                    .chars()
+                    .take(block_width)
+                    .collect::<String>(),
+                    block_width = block_width
+                ),
+                None => format!(
+                    "{:^block_width$}",
+                    self.name.chars().take(block_width).collect::<String>(),
+                    block_width = block_width
+                ),
+            };
 
             buf.set_string(
                 inner.left() + relative_start as u16,
                 inner.top() + self.depth - get_scroll(bound.selected_depth, inner.height),
                 padded_string,
-                Style::default().fg(Color::White).bg(self.color()),
+                {
+                    let mut style = self.style();
+                    if self.aggregate.is_some() {
+                        style = style.italic();
+                    }
+                    if self.pinned {
+                        style = style.bold().underlined();
+                    }
+                    if self.selected {
+                        style = style.reversed();
+                    }
+                    style
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{Terminal, backend::TestBackend, style::Modifier};
+
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::priority::{CompactionRule, SpiedRecordQueueMap};
+    use crate::testing::{sample, stack};
+
+    // one character per second inside the borders
+    fn setup() -> (ManualClock, SpiedRecordQueueMap, ViewPortBounds) {
+        let clock = ManualClock::default();
+        let mut queues = SpiedRecordQueueMap::default();
+        queues.with_clock(Arc::new(clock.clone()));
+        let mut viewport = ViewPortBounds::default().with_clock(Arc::new(clock.clone()));
+        viewport.width = Duration::from_secs(30);
+
+        for names in [
+            &["main", "load"][..],
+            &["main", "load"],
+            &["main", "train"],
+            &["main", "train"],
+        ] {
+            queues.increment(&stack(1, 1, names, true, false));
+            clock.advance(Duration::from_secs(8));
+        }
+        (clock, queues, viewport)
+    }
+
+    fn draw(queues: &SpiedRecordQueueMap, viewport: &mut ViewPortBounds) -> Buffer {
+        draw_in(queues, viewport, 32)
+    }
+
+    fn draw_in(queues: &SpiedRecordQueueMap, viewport: &mut ViewPortBounds, width: u16) -> Buffer {
+        let mut terminal = Terminal::new(TestBackend::new(width, 5)).unwrap();
+        terminal
+            .draw(|frame| {
+                frame.render_stateful_widget(
+                    TimelineWidget::from_queue(queues.get(&(1, 1))).blocked(),
+                    frame.area(),
+                    viewport,
+                )
+            })
+            .unwrap();
+        terminal.backend().buffer().clone()
+    }
+
+    fn render(queues: &SpiedRecordQueueMap, viewport: &mut ViewPortBounds) -> Vec<String> {
+        let buffer = draw(queues, viewport);
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_render_latest() {
+        let (_, queues, mut viewport) = setup();
+        assert_eq!(
+            render(&queues, &mut viewport),
+            vec![
+                "╭-00:32──────❮30s❯──────────Now╮",
+                "│         main                 │",
+                "│   load      train            │",
+                "│                              │",
+                "╰test.py::main──────────────0/2╯",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_moved_left() {
+        let (clock, queues, mut viewport) = setup();
+        viewport.move_left();
+        // the view no longer follows the clock
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            render(&queues, &mut viewport),
+            vec![
+                "╭-00:37──────❮30s❯───────-00:20╮",
+                "│                   main       │",
+                "│                 load    train│",
+                "│                              │",
+                "╰test.py::main──────────────0/2╯",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dim_events_seen_in_few_samples() {
+        let (_, queues, mut viewport) = setup();
+        let buffer = draw(&queues, &mut viewport);
+        // `main` was seen in four samples, `load` and `train` in two each
+        assert!(!buffer[(11, 1)].modifier.contains(Modifier::DIM));
+        assert!(buffer[(5, 2)].modifier.contains(Modifier::DIM));
+        assert!(buffer[(14, 2)].modifier.contains(Modifier::DIM));
+    }
+
+    #[test]
+    fn test_select_finished_event() {
+        let (_, queues, mut viewport) = setup();
+        // wide enough for the footer, still one character per second
+        viewport.width = Duration::from_secs(40);
+        viewport.selected_depth = 1;
+        viewport.step_selected_event(queues.get(&(1, 1)).unwrap(), false);
+        let buffer = draw_in(&queues, &mut viewport, 42);
+        assert_eq!(
+            (1..41).map(|x| buffer[(x, 4)].symbol()).collect::<String>(),
+            "test.py::load 12.0s 2 samples────────1/2"
+        );
+        assert!(buffer[(15, 2)].modifier.contains(Modifier::REVERSED));
+        assert!(!buffer[(24, 2)].modifier.contains(Modifier::REVERSED));
+
+        // nothing finished before `load`
+        viewport.step_selected_event(queues.get(&(1, 1)).unwrap(), false);
+        assert!(viewport.selected_event.is_some());
+        viewport.move_up();
+        assert!(viewport.selected_event.is_none());
+    }
+
+    #[test]
+    fn test_render_aggregate() {
+        let clock = ManualClock::default();
+        let mut queues = SpiedRecordQueueMap::default();
+        queues.with_clock(Arc::new(clock.clone()));
+        queues.with_compaction(vec![CompactionRule {
+            older_than: Duration::from_secs(5),
+            shorter_than: Duration::from_secs(5),
+            bucket: Duration::from_secs(20),
+        }]);
+        let mut viewport = ViewPortBounds::default().with_clock(Arc::new(clock.clone()));
+        viewport.width = Duration::from_secs(30);
+
+        for second in 0..26 {
+            let names = match second {
+                0..20 if second % 4 == 3 => &["main", "log"][..],
+                0..20 => &["main", "step"],
+                _ => &["main"],
+            };
+            queues.increment(&stack(1, 1, names, true, false));
+            clock.advance(Duration::from_secs(1));
+        }
+        // 20s of alternating `step` and `log` ended over 5s ago, so they are merged
+        assert_eq!(
+            render(&queues, &mut viewport),
+            vec![
+                "╭-00:26──────❮30s❯──────────Now╮",
+                "│              main            │",
+                "│    ░░░░5×step 74%░░░░░       │",
+                "│                              │",
+                "╰test.py::main──────────────0/1╯",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_gap() {
+        let clock = ManualClock::default();
+        let mut queues = SpiedRecordQueueMap::default();
+        queues.with_clock(Arc::new(clock.clone()));
+        let mut viewport = ViewPortBounds::default().with_clock(Arc::new(clock.clone()));
+        viewport.width = Duration::from_secs(30);
+
+        let period = Duration::from_secs(1);
+        for offset in [0, 1, 2, 3, 4, 5, 18, 19, 20, 21, 22, 23, 24, 25] {
+            let at = clock.now() + Duration::from_secs(offset);
+            queues.increment_sample(
+                1,
+                &sample(vec![stack(1, 1, &["main"], true, false)]),
+                at,
+                period,
             );
         }
+        clock.advance(Duration::from_secs(30));
+
+        assert_eq!(queues.gap_count(), 1);
+        assert_eq!(
+            render(&queues, &mut viewport),
+            vec![
+                "╭-00:30──────❮30s❯──────────Now╮",
+                "│main ╱╱╱╱╱╱╱╱╱╱╱╱╱ main       │",
+                "│     ╱╱╱no data╱╱╱            │",
+                "│     ╱╱╱╱╱╱╱╱╱╱╱╱╱            │",
+                "╰test.py::main──────────────0/1╯",
+            ]
+        );
     }
 }
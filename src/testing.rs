@@ -0,0 +1,48 @@
+//! Helpers shared by the unit tests of several modules.
+
+use py_spy::sampler::Sample;
+use py_spy::{Frame, StackTrace};
+use remoteprocess::Pid;
+
+/// Samples thread `tid` of `pid` inside the functions `names` of test.py,
+/// listed outermost first.
+pub(crate) fn stack(
+    pid: Pid,
+    tid: u64,
+    names: &[&str],
+    active: bool,
+    owns_gil: bool,
+) -> StackTrace {
+    StackTrace {
+        pid,
+        thread_id: tid,
+        thread_name: None,
+        os_thread_id: None,
+        active,
+        owns_gil,
+        // py-spy lists the innermost frame first
+        frames: names
+            .iter()
+            .rev()
+            .map(|name| Frame {
+                name: name.to_string(),
+                filename: "test.py".to_string(),
+                module: None,
+                short_filename: None,
+                line: 1,
+                locals: None,
+                is_entry: false,
+            })
+            .collect(),
+        process_info: None,
+    }
+}
+
+/// A sample that py-spy took in time and without errors.
+pub(crate) fn sample(traces: Vec<StackTrace>) -> Sample {
+    Sample {
+        traces,
+        sampling_errors: None,
+        late: None,
+    }
+}
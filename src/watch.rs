@@ -0,0 +1,98 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Error, anyhow};
+use regex::Regex;
+use remoteprocess::Pid;
+
+use crate::app::{LiveSampler, SamplerOps};
+use crate::control::SamplerControl;
+use crate::discover::{PythonProcess, python_processes};
+use crate::errors::AppError;
+use crate::priority::SpiedRecordQueueMap;
+
+const REATTACH_POLL: Duration = Duration::from_millis(500);
+
+/// How a restarted target is recognised among the running python processes.
+#[derive(Debug, Clone)]
+pub enum WatchTarget {
+    Pattern(Regex),
+    CommandLine(String),
+}
+
+impl WatchTarget {
+    /// Watches for whatever runs the same command line as `pid` does now.
+    pub fn same_as(pid: Pid) -> Result<Self, Error> {
+        python_processes()
+            .into_iter()
+            .find(|process| process.pid == pid)
+            .map(|process| Self::CommandLine(process.command_line))
+            .ok_or_else(|| anyhow!("{} is not a running python process", pid))
+    }
+
+    fn matches(&self, process: &PythonProcess) -> bool {
+        match self {
+            Self::Pattern(pattern) => pattern.is_match(&process.command_line),
+            Self::CommandLine(command_line) => process.command_line == *command_line,
+        }
+    }
+}
+
+/// Samples a process and, whenever it exits, waits for it to come back and
+/// samples the new process too, keeping the history of the old one.
+pub struct WatchingSampler {
+    live: LiveSampler,
+    target: WatchTarget,
+}
+
+impl WatchingSampler {
+    pub fn new(live: LiveSampler, target: WatchTarget) -> Self {
+        Self { live, target }
+    }
+}
+
+fn reattach(
+    live: &mut LiveSampler,
+    target: &WatchTarget,
+    control: &SamplerControl,
+) -> Result<(), Error> {
+    let exited = live.pid();
+    loop {
+        thread::sleep(REATTACH_POLL);
+        // workers sharing a command line must not end up sampled twice
+        let Some(process) = python_processes().into_iter().find(|process| {
+            process.pid != exited && target.matches(process) && control.claim(process.pid)
+        }) else {
+            continue;
+        };
+        // a process that has only just started may not have an interpreter to sample yet
+        if live.attach(process.pid, control).is_ok() {
+            return Ok(());
+        }
+        control.release(process.pid);
+    }
+}
+
+impl SamplerOps for WatchingSampler {
+    fn push_to_queue(
+        mut self,
+        record_queue_map: Arc<RwLock<SpiedRecordQueueMap>>,
+        control: SamplerControl,
+    ) -> Result<(), Error> {
+        control.claim(self.live.pid());
+        loop {
+            self.live.sample(&record_queue_map, &control)?;
+            record_queue_map
+                .write()
+                .map_err(|_| AppError::SamplerSenderError)?
+                .mark_exited(self.live.pid());
+            control.release(self.live.pid());
+            reattach(&mut self.live, &self.target, &control)?;
+        }
+    }
+
+    fn is_controllable(&self) -> bool {
+        true
+    }
+}